@@ -0,0 +1,6 @@
+use std::io;
+
+fn main() {
+    let stdin = io::stdin();
+    jack_compiler::lsp::serve(stdin.lock(), io::stdout());
+}
@@ -0,0 +1,52 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use jack_compiler::compiler::Compiler;
+use jack_compiler::formatter::format_source;
+use jack_compiler::options::Options;
+
+const USAGE: &str = "usage: jackfmt [--check] [--extensions] <filename>.jack | <dirname> ...";
+
+fn main() {
+    let mut check = false;
+    let mut options = Options::default();
+    let mut sources = vec![];
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--check" => { check = true; },
+            "--extensions" => { options.extensions = true; },
+            a if a.starts_with("--") => { eprintln!("unknown option {}\n{}", a, USAGE); process::exit(2); },
+            a => { sources.extend(Compiler::sources(Path::new(a))); }
+        }
+    }
+    if sources.is_empty() {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let mut failed = false;
+    for f in sources.iter() {
+        let src = match fs::read_to_string(f) {
+            Ok(src) => src,
+            Err(e) => { eprintln!("{}: {}", f.display(), e); failed = true; continue; }
+        };
+        let formatted = match format_source(&src, &options) {
+            Ok(formatted) => formatted,
+            Err(e) => { eprintln!("{}: error: {}", f.display(), e); failed = true; continue; }
+        };
+        if formatted == src {
+            continue;
+        }
+        if check {
+            println!("{}: not formatted", f.display());
+            failed = true;
+        } else {
+            fs::write(f, formatted).expect("cannot write formatted file");
+            println!("{}: formatted", f.display());
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
@@ -1,31 +1,498 @@
-use std::path::Path;
-use std::fs::File;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime};
 use crate::tokenizer::*;
 use crate::engine::*;
+use crate::declaration::*;
+use crate::registry::*;
+use crate::diagnostic::*;
+use crate::lint::*;
+use crate::options::*;
+use crate::symbols::*;
+use crate::source_map::*;
 
-pub struct Compiler;
+#[derive(Debug, Clone)]
+pub struct BadEmitError(pub String);
+
+impl fmt::Display for BadEmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown output {}, expected vm, map or symbols", self.0)
+    }
+}
+
+/// The outcome of compiling a file: its warnings, or every diagnostic when one is an error.
+pub type CompileResult = Result<Vec<Diagnostic>, Vec<Diagnostic>>;
+
+/// What `Compiler::run` produces for each source file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Emit {
+    Vm,      // the .vm file next to the source
+    Map,     // the .vm file and its .vm.map source map, see `SourceMap`
+    Symbols, // the symbol table and cross-reference report, on the standard output
+}
+
+impl FromStr for Emit {
+    type Err = BadEmitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vm"      => Ok(Emit::Vm),
+            "map"     => Ok(Emit::Map),
+            "symbols" => Ok(Emit::Symbols),
+            _         => Err(BadEmitError(s.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Compiler {
+    options: Options,
+    stubs: Registry, // declarations of the classes whose sources are not compiled, the OS ones at least
+    emit: Vec<Emit>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Compiler::new(Options::default())
+    }
+}
 
 impl Compiler {
-    pub fn run(source: &Path) {
+    pub fn new(options: Options) -> Self {
+        Compiler { options, stubs: Registry::os(), emit: vec![Emit::Vm] }
+    }
+
+    pub fn set_emit(&mut self, emit: Vec<Emit>) {
+        self.emit = emit;
+    }
+
+    /// Reads the classes of a declaration file, known to every compilation unless a source declares the same class.
+    pub fn load_stubs(&mut self, path: &Path) -> Result<(), Diagnostic> {
+        self.stubs.load_stub_file(path, &self.options)
+    }
+
+    pub fn run(&self, source: &Path) -> bool {
+        let mut ok = true;
+        let registry = self.registry(source);
+        for f in Compiler::sources(source) {
+            for emit in self.emit.iter() {
+                let result = match emit {
+                    Emit::Vm => self.compile_file(&f, &registry),
+                    Emit::Map if self.emit.contains(&Emit::Vm) => Ok(vec![]), // written with the .vm file
+                    Emit::Map => self.compile_file(&f, &registry),
+                    Emit::Symbols => self.symbol_report(&f, &registry).map(|report| { print!("{}", report); vec![] }).map_err(|d| vec![d]),
+                };
+                let diagnostics = result.unwrap_or_else(|diagnostics| {
+                    ok = false;
+                    diagnostics
+                });
+                for d in diagnostics {
+                    eprintln!("{}", d);
+                }
+            }
+        }
+        ok
+    }
+
+    /// Lists the .jack files to compile: the file itself, or every .jack file directly under a directory.
+    pub fn sources(source: &Path) -> Vec<PathBuf> {
         if source.is_dir() {
-            for f in source.read_dir().expect("read_dir call failed") {
-                if let Ok(f) = f {
-                    if f.path().extension().unwrap() == "jack" {
-                        let fin = File::open(f.path()).expect("cannot create source file");
-                        let fout = File::create(f.path().with_extension("vm")).expect("cannot create output file");
-                        let t = Tokenizer::new(fin);
-                        let mut e = Engine::new(t, fout);
-                        e.compile();
+            let mut files: Vec<PathBuf> = source.read_dir().expect("read_dir call failed")
+                .flatten()
+                .map(|f| f.path())
+                .filter(|p| p.extension().is_some_and(|ext| ext == "jack"))
+                .collect();
+            files.sort();
+            files
+        } else {
+            vec![source.to_path_buf()]
+        }
+    }
+
+    /// The classes of the program `source` belongs to, `source` being one of its files or its directory:
+    /// the stubs, then the outlines of the .jack files in the directory, so that each class can check its calls
+    /// to the others and use their constants. A source declaring an OS class replaces its bundled declaration.
+    /// It is built once and shared by the files compiled together.
+    pub fn registry(&self, source: &Path) -> Registry {
+        let dir = match source.is_dir() {
+            true  => source,
+            false => source.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new(".")),
+        };
+        self.registry_with(Compiler::sources(dir).iter().filter_map(|f| self.outline(f)))
+    }
+
+    /// The stubs, then the given outlines, with the constants naming other classes evaluated.
+    pub fn registry_with(&self, classes: impl IntoIterator<Item = ClassDecl>) -> Registry {
+        let mut registry = self.stubs.clone();
+        for c in classes {
+            registry.add(c);
+        }
+        registry.resolve_constants(self.options.precedence);
+        registry
+    }
+
+    /// The outline of the class declared in a .jack file, None if it cannot be read.
+    pub fn outline(&self, source: &Path) -> Option<ClassDecl> {
+        File::open(source).ok()
+            .and_then(|fin| catch_panic(|| Tokenizer::with_options(fin, &self.options)).ok())
+            .and_then(|t| ClassDecl::parse_with(&t, &self.options))
+    }
+
+    /// Compiles one .jack file into the .vm file next to it, and its .vm.map when `Emit::Map` is requested,
+    /// returning the warnings found. On failure every diagnostic is returned: the lexical errors, or the errors
+    /// and warnings of the engine, ending with its panic turned into an error located at the last token read.
+    /// The partially written output is removed. `registry` holds the classes of the program, see `registry`.
+    pub fn compile_file(&self, source: &Path, registry: &Registry) -> CompileResult {
+        let file_name = source.to_string_lossy().into_owned();
+        let fin = File::open(source)
+            .map_err(|e| vec![Diagnostic::error(None, format!("cannot open source file: {}", e)).in_file(&file_name)])?;
+        let out_path = source.with_extension("vm");
+        let fout = File::create(&out_path)
+            .map_err(|e| vec![Diagnostic::error(None, format!("cannot create output file: {}", e)).in_file(&file_name)])?;
+
+        let diagnostics = match catch_panic(|| Tokenizer::with_options(fin, &self.options)) {
+            Ok(t) if !t.errors.is_empty() => t.errors.clone(),
+            Ok(t) => {
+                let mut e = Engine::with_options(t, fout, self.options.clone());
+                e.set_registry(registry.clone());
+                let result = catch_panic(|| e.compile());
+                let mut diagnostics = e.diagnostics().to_vec();
+                match result {
+                    Err(msg) => diagnostics.push(Diagnostic::error(e.current_span(), msg)),
+                    Ok(()) if diagnostics.iter().any(|d| d.severity == Severity::Error) => (),
+                    Ok(()) if self.emit.contains(&Emit::Map) => diagnostics.extend(self.write_source_map(source, &e).err()),
+                    Ok(()) => (),
+                }
+                diagnostics
+            },
+            Err(msg) => vec![Diagnostic::error(None, msg)],
+        };
+        let diagnostics: Vec<Diagnostic> = diagnostics.into_iter().map(|d| d.in_file(&file_name)).collect();
+        if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+            let _ = fs::remove_file(&out_path);
+            let _ = fs::remove_file(source.with_extension("vm.map"));
+            return Err(diagnostics);
+        }
+        Ok(diagnostics)
+    }
+
+    fn write_source_map(&self, source: &Path, engine: &Engine) -> Result<(), Diagnostic> {
+        let file = source.file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_default();
+        let map = SourceMap::from_engine(&file, engine);
+        fs::write(source.with_extension("vm.map"), format!("{}\n", map.to_json()))
+            .map_err(|e| Diagnostic::error(None, format!("cannot create source map: {}", e)))
+    }
+
+    /// Compiles one .jack file without writing any output and reports its variables, see `symbol_report`.
+    pub fn symbol_report(&self, source: &Path, registry: &Registry) -> Result<String, Diagnostic> {
+        let file_name = source.to_string_lossy().into_owned();
+        let fin = File::open(source)
+            .map_err(|e| Diagnostic::error(None, format!("cannot open source file: {}", e)).in_file(&file_name))?;
+        let result = match catch_panic(|| Tokenizer::with_options(fin, &self.options)) {
+            Ok(t) if !t.errors.is_empty() => Err(t.errors[0].clone()),
+            Ok(t) => {
+                let mut e = Engine::with_options(t, std::io::sink(), self.options.clone());
+                e.set_registry(registry.clone());
+                match catch_panic(|| e.compile()) {
+                    Ok(()) => match e.diagnostics().iter().find(|d| d.severity == Severity::Error) {
+                        Some(d) => Err(d.clone()),
+                        None => Ok(symbol_report(&file_name, &e)),
+                    },
+                    Err(msg) => Err(Diagnostic::error(e.current_span(), msg)),
+                }
+            },
+            Err(msg) => Err(Diagnostic::error(None, msg)),
+        };
+        result.map_err(|d| d.in_file(&file_name))
+    }
+
+    /// Runs the lint rules enabled in `config` over one .jack file without writing any output.
+    /// A compile error ends the check and is reported after the warnings found up to that point.
+    pub fn lint_file(&self, source: &Path, registry: &Registry, config: &LintConfig) -> Vec<Diagnostic> {
+        let file_name = source.to_string_lossy().into_owned();
+        let fin = match File::open(source) {
+            Ok(fin) => fin,
+            Err(e) => { return vec![Diagnostic::error(None, format!("cannot open source file: {}", e)).in_file(&file_name)]; }
+        };
+        let mut diagnostics = match catch_panic(|| Tokenizer::with_options(fin, &self.options)) {
+            Ok(t) if !t.errors.is_empty() => t.errors.clone(),
+            Ok(t) => {
+                let mut e = Engine::with_options(t, std::io::sink(), self.options.clone());
+                e.set_registry(registry.clone());
+                e.enable_lints(config.clone());
+                let result = catch_panic(|| e.compile());
+                let mut diagnostics = e.diagnostics().to_vec();
+                if let Err(msg) = result {
+                    diagnostics.push(Diagnostic::error(e.current_span(), msg));
+                }
+                diagnostics
+            },
+            Err(msg) => vec![Diagnostic::error(None, msg)],
+        };
+        for d in diagnostics.iter_mut() {
+            d.file = file_name.clone();
+        }
+        diagnostics
+    }
+
+    /// Polls the source every `interval` and recompiles the .jack files that changed. Never returns.
+    pub fn watch(&self, source: &Path, interval: Duration) {
+        let mut watcher = Watcher::new(self.clone(), source);
+        loop {
+            let results = watcher.poll();
+            if !results.is_empty() {
+                println!("[watch] compiling {} file(s)", results.len());
+                let mut errors = 0;
+                for (path, result) in results.iter() {
+                    match result {
+                        Ok(warnings) => {
+                            for d in warnings.iter() {
+                                println!("{}", d);
+                            }
+                            println!("{}: ok", path.display());
+                        },
+                        Err(diagnostics) => {
+                            for d in diagnostics.iter() {
+                                println!("{}", d);
+                            }
+                            errors += diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+                        }
                     }
                 }
+                println!("[watch] {} error(s), waiting for changes...", errors);
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+
+/// Remembers the modification time, size and class outline of every .jack file seen, so that only changed files
+/// are recompiled. Every class checks its calls and constants against the outlines of the others, so a change
+/// to an outline, or a file added or removed, recompiles them all.
+pub struct Watcher {
+    compiler: Compiler,
+    source: PathBuf,
+    stamps: HashMap<PathBuf, (SystemTime, u64)>,
+    outlines: HashMap<PathBuf, Option<ClassDecl>>,
+}
+
+impl Watcher {
+    pub fn new(compiler: Compiler, source: &Path) -> Self {
+        Watcher {
+            compiler,
+            source: source.to_path_buf(),
+            stamps: HashMap::new(),
+            outlines: HashMap::new(),
+        }
+    }
+
+    /// Compiles every file added or modified since the previous poll (all files on the first poll),
+    /// or every file when an outline changed.
+    pub fn poll(&mut self) -> Vec<(PathBuf, CompileResult)> {
+        let files = Compiler::sources(&self.source);
+        let count = self.stamps.len();
+        self.stamps.retain(|p, _| files.contains(p));
+        self.outlines.retain(|p, _| files.contains(p));
+        let mut all = self.stamps.len() != count;
+        let mut changed = vec![];
+        for f in files.iter() {
+            let stamp = match fs::metadata(f) {
+                Ok(m) => (m.modified().unwrap_or(SystemTime::UNIX_EPOCH), m.len()),
+                Err(_) => { continue; } // removed while listing
+            };
+            if self.stamps.get(f) != Some(&stamp) {
+                self.stamps.insert(f.clone(), stamp);
+                // compared as printed, without the locations a change in a body shifts
+                let outline = self.compiler.outline(f);
+                let printed = |o: &Option<ClassDecl>| o.as_ref().map(|c| c.to_string());
+                if self.outlines.get(f).map(printed) != Some(printed(&outline)) {
+                    all = true;
+                }
+                self.outlines.insert(f.clone(), outline);
+                changed.push(f.clone());
             }
+        }
+        if all {
+            changed = files.iter().filter(|f| self.stamps.contains_key(*f)).cloned().collect();
+        }
+        if changed.is_empty() {
+            return vec![];
+        }
+        let registry = self.compiler.registry_with(files.iter().filter_map(|f| self.outlines.get(f).cloned().flatten()));
+        changed.into_iter()
+            .map(|f| {
+                let result = self.compiler.compile_file(&f, &registry);
+                (f, result)
+            })
+            .collect()
+    }
+}
+
+thread_local! {
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Runs f, returning the panic message on failure. The message is not printed: a hook installed once wraps the
+// previous one and skips it while the panicking thread is inside `catch_panic`, so other threads keep their output.
+pub(crate) fn catch_panic<T, F: FnOnce() -> T>(f: F) -> Result<T, String> {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING.with(|c| c.get()) {
+                previous(info);
+            }
+        }));
+    });
+    let outer = CATCHING.with(|c| c.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.with(|c| c.set(outer));
+    result.map_err(|payload| {
+        if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
         } else {
-            let fin = File::open(source).expect("cannot create source file");
-            let fout = File::create(source.with_extension("vm")).expect("cannot create output file");
-            let t = Tokenizer::new(fin);
-            let mut e = Engine::new(t, fout);
-            e.compile();
+            String::from("unknown error")
         }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("jackc_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_catch_panic_in_threads() {
+        let handles: Vec<_> = (0..8).map(|i| thread::spawn(move || {
+            assert_eq!(catch_panic(|| i), Ok(i));
+            catch_panic(|| -> () { panic!("error {}", i) })
+        })).collect();
+        for (i, h) in handles.into_iter().enumerate() {
+            assert_eq!(h.join().unwrap(), Err(format!("error {}", i)));
+        }
+    }
+
+    #[test]
+    fn test_compile_file_reports_error() {
+        let dir = scratch_dir("compile_error");
+        let src = dir.join("Main.jack");
+        fs::write(&src, "class Main {\n  function void main() {\n    let x = 1;\n  }\n}\n").unwrap();
+        let d = Compiler::default().compile_file(&src, &Compiler::default().registry(&src)).unwrap_err();
+        assert_eq!(d.len(), 1);
+        assert_eq!(d[0].message, "variable x is not registered");
+        assert_eq!(d[0].span.map(|s| (s.line, s.col)), Some((3, 9)));
+        assert!(!src.with_extension("vm").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_constants_across_classes() {
+        let dir = scratch_dir("constants");
+        fs::write(dir.join("Board.jack"), "class Board {\n  const int SIZE = 8;\n  const int CELLS = Board.SIZE * SIZE;\n}\n").unwrap();
+        fs::write(dir.join("Main.jack"), "class Main {\n  function int main() {\n    return Board.CELLS - 1;\n  }\n}\n").unwrap();
+        let compiler = Compiler::new(Options { extensions: true, ..Options::default() });
+        assert_eq!(compiler.registry(&dir.join("Main.jack")).constant("Board", "CELLS"), Some(64));
+        compiler.compile_file(&dir.join("Main.jack"), &compiler.registry(&dir)).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("Main.vm")).unwrap(),
+            "function Main.main 0\npush constant 64\npush constant 1\nsub\nreturn\n");
+        // constants naming those of other classes are evaluated in dependency order
+        fs::write(dir.join("Board.jack"), "class Board {\n  const int SIZE = Main.SIDE;\n  const int CELLS = SIZE * SIZE;\n}\n").unwrap();
+        fs::write(dir.join("Main.jack"), "class Main {\n  const int SIDE = 6;\n  const int LAST = Board.CELLS - 1;\n  function int main() {\n    return LAST;\n  }\n}\n").unwrap();
+        compiler.compile_file(&dir.join("Main.jack"), &compiler.registry(&dir)).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("Main.vm")).unwrap(), "function Main.main 0\npush constant 35\nreturn\n");
+        fs::write(dir.join("Board.jack"), "class Board {\n  const int SIZE = Main.LAST;\n}\n").unwrap();
+        fs::write(dir.join("Main.jack"), "class Main {\n  const int LAST = Board.SIZE - 1;\n}\n").unwrap();
+        let d = compiler.compile_file(&dir.join("Main.jack"), &compiler.registry(&dir)).unwrap_err();
+        assert_eq!(d[0].message, "constant Main.LAST is defined in terms of itself: Main.LAST -> Board.SIZE -> Main.LAST");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_source_map_file() {
+        let dir = scratch_dir("source_map");
+        let src = dir.join("Main.jack");
+        fs::write(&src, "class Main {\n  function int main() {\n    return 1;\n  }\n}\n").unwrap();
+        let mut compiler = Compiler::default();
+        compiler.set_emit(vec![Emit::Map]);
+        assert!(compiler.run(&src));
+        assert_eq!(fs::read_to_string(dir.join("Main.vm")).unwrap(), "function Main.main 0\npush constant 1\nreturn\n");
+        assert_eq!(fs::read_to_string(dir.join("Main.vm.map")).unwrap(),
+            "{\"version\":1,\"file\":\"Main.jack\",\"variables\":[],\"functions\":[{\"name\":\"Main.main\",\"instructions\":[[2,16,2,20],[3,12,3,13],[3,13,3,14]],\"variables\":[]}]}\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_declaration_error() {
+        let dir = scratch_dir("duplicate");
+        let src = dir.join("Main.jack");
+        fs::write(&src, "class Main {\n  field int y;\n  method void main(int x) {\n    var int y, x;\n    var int x;\n    return;\n  }\n}\n").unwrap();
+        let d = Compiler::default().compile_file(&src, &Compiler::default().registry(&src)).unwrap_err();
+        let file = src.to_string_lossy();
+        let messages: Vec<String> = d.iter().map(|d| d.to_string()).collect();
+        assert_eq!(messages, vec![
+            format!("{}:4:13: warning: var y shadows a class variable\n{}:2:13: note: y is declared here", file, file),
+            format!("{}:4:16: error: x is already declared in this scope\n{}:3:24: note: previous declaration of x", file, file),
+            format!("{}:5:13: error: x is already declared in this scope\n{}:3:24: note: previous declaration of x", file, file),
+        ]);
+        assert!(!src.with_extension("vm").exists());
+        // warnings alone do not stop the compilation
+        fs::write(&src, "class Main {\n  field int y;\n  method void main() {\n    var int y;\n    let y = 1;\n    return;\n  }\n}\n").unwrap();
+        let warnings = Compiler::default().compile_file(&src, &Compiler::default().registry(&src)).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "var y shadows a class variable");
+        assert!(src.with_extension("vm").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_watcher_recompiles_changed_files() {
+        let dir = scratch_dir("watch");
+        fs::write(dir.join("Main.jack"), "class Main { function void main() { return; } }").unwrap();
+        fs::write(dir.join("Foo.jack"), "class Foo { function int f() { return 1; } }").unwrap();
+        let mut w = Watcher::new(Compiler::default(), &dir);
+        assert_eq!(w.poll().len(), 2);
+        assert_eq!(w.poll().len(), 0);
+        fs::write(dir.join("Foo.jack"), "class Foo { function int f() { return 12; } }").unwrap();
+        let results = w.poll();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, dir.join("Foo.jack"));
+        assert!(results[0].1.is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_watcher_recompiles_all_files_when_an_outline_changes() {
+        let dir = scratch_dir("watch_outline");
+        fs::write(dir.join("Main.jack"), "class Main { function void main() { do Foo.f(); return; } }").unwrap();
+        fs::write(dir.join("Foo.jack"), "class Foo { function int f() { return 1; } }").unwrap();
+        let mut w = Watcher::new(Compiler::default(), &dir);
+        assert!(w.poll().iter().all(|(_, r)| r.is_ok()));
+        fs::write(dir.join("Foo.jack"), "class Foo { function int f(int x) { return x; } }").unwrap();
+        let results = w.poll();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].0, dir.join("Main.jack"));
+        assert!(results[1].1.is_err());
+        fs::remove_file(dir.join("Foo.jack")).unwrap();
+        let results = w.poll();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, dir.join("Main.jack"));
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
 
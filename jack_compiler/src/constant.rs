@@ -0,0 +1,154 @@
+use crate::tokenizer::*;
+use crate::keyword::*;
+use crate::symbol::*;
+
+/// Evaluates the initializer of a `const` declaration with the 16-bit arithmetic of the Hack platform,
+/// grouping operators as the engine would compile them: left to right, or by precedence with `precedence`.
+/// `lookup` resolves the other constants it names, either `NAME` or `Class.NAME`.
+pub fn evaluate(tokens: &[Token], precedence: bool, lookup: &dyn Fn(&str) -> Option<i16>) -> Result<i16, String> {
+    let mut e = Evaluator { tokens, pos: 0, precedence, lookup };
+    let value = e.expression()?;
+    match e.peek() {
+        None => Ok(value),
+        Some(t) => Err(format!("unexpected token in constant expression: {:?}", t)),
+    }
+}
+
+struct Evaluator<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    precedence: bool,
+    lookup: &'a dyn Fn(&str) -> Option<i16>,
+}
+
+impl Evaluator<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+
+    fn expression(&mut self) -> Result<i16, String> {
+        if self.precedence {
+            return self.binary_expression(0);
+        }
+        // term (op term)*
+        let mut value = self.term()?;
+        while let Some(&Token::Symbol(op)) = self.peek() {
+            if op.precedence().is_none() {
+                break;
+            }
+            self.next();
+            value = apply(op, value, self.term()?)?;
+        }
+        Ok(value)
+    }
+
+    fn binary_expression(&mut self, min_precedence: u8) -> Result<i16, String> {
+        let mut value = self.term()?;
+        while let Some(&Token::Symbol(op)) = self.peek() {
+            let precedence = match op.precedence() {
+                Some(p) if p >= min_precedence => p,
+                _ => { break; }
+            };
+            self.next();
+            value = apply(op, value, self.binary_expression(precedence + 1)?)?;
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<i16, String> {
+        match self.next().cloned() {
+            Some(Token::IntConst(i)) if i > 32767 => Err(format!("integer constant {} is out of range (0..32767)", i)),
+            Some(Token::IntConst(i)) => Ok(i as i16),
+            Some(Token::Keyword(Keyword::True)) => Ok(-1),
+            Some(Token::Keyword(Keyword::False | Keyword::Null)) => Ok(0),
+            Some(Token::Identifier(name)) => {
+                // constantName | className '.' constantName
+                let name = if self.peek() == Some(&Token::Symbol(Symbol::Dot)) {
+                    self.next();
+                    match self.next() {
+                        Some(Token::Identifier(member)) => format!("{}.{}", name, member),
+                        t => { return Err(format!("constant name expected after '{}.', found {:?}", name, t)); }
+                    }
+                } else {
+                    name
+                };
+                (self.lookup)(&name).ok_or_else(|| format!("{} is not a constant defined before this one", name))
+            },
+            Some(Token::Symbol(Symbol::Minus)) => {
+                if self.peek() == Some(&Token::IntConst(INT_CONST_MAX)) {
+                    self.next();
+                    return Ok(i16::MIN);
+                }
+                Ok(self.term()?.wrapping_neg())
+            },
+            Some(Token::Symbol(Symbol::Not)) => Ok(!self.term()?),
+            Some(Token::Symbol(Symbol::ParenL)) => {
+                let value = self.expression()?;
+                match self.next() {
+                    Some(Token::Symbol(Symbol::ParenR)) => Ok(value),
+                    t => Err(format!("')' expected in constant expression, found {:?}", t)),
+                }
+            },
+            Some(t) => Err(format!("{:?} is not allowed in a constant expression", t)),
+            None => Err(String::from("constant expression expected")),
+        }
+    }
+}
+
+fn apply(op: Symbol, a: i16, b: i16) -> Result<i16, String> {
+    let truth = |b: bool| if b { -1 } else { 0 };
+    Ok(match op {
+        Symbol::Plus        => a.wrapping_add(b),
+        Symbol::Minus       => a.wrapping_sub(b),
+        Symbol::Asterisk    => a.wrapping_mul(b),
+        Symbol::Slash if b == 0 => { return Err(String::from("division by zero in constant expression")); },
+        Symbol::Slash       => a.wrapping_div(b),
+        Symbol::And         => a & b,
+        Symbol::Or          => a | b,
+        Symbol::LessThan    => truth(a < b),
+        Symbol::GreaterThan => truth(a > b),
+        Symbol::Equal       => truth(a == b),
+        // the same values as the code the engine emits for the short-circuit operators
+        Symbol::AndAnd      => truth(a != 0 && b != 0),
+        Symbol::OrOr        => truth(a != 0 || b != 0),
+        _ => unreachable!(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::*;
+
+    fn eval(src: &str, precedence: bool) -> Result<i16, String> {
+        let options = Options { extensions: true, ..Options::default() };
+        let t = Tokenizer::with_options(src.as_bytes(), &options);
+        let tokens: Vec<Token> = t.tokens.into_iter().rev().collect();
+        evaluate(&tokens, precedence, &|name| match name {
+            "WIDTH" => Some(512),
+            "Screen.ROWS" => Some(256),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(eval("1 + 2 * 3", false), Ok(9));
+        assert_eq!(eval("1 + 2 * 3", true), Ok(7));
+        assert_eq!(eval("WIDTH / 16 * Screen.ROWS", false), Ok(8192));
+        assert_eq!(eval("-32768", false), Ok(-32768));
+        assert_eq!(eval("32767 + 1", false), Ok(-32768));
+        assert_eq!(eval("~0x00ff & 0x7fff", false), Ok(0x7f00));
+        assert_eq!(eval("(3 > 2) | ('a' = 97)", false), Ok(-1));
+        assert_eq!(eval("0 && 1 || 5", false), Ok(-1));
+        assert_eq!(eval("1 && 2", false), Ok(-1));
+        assert_eq!(eval("1 / (2 - 2)", false), Err(String::from("division by zero in constant expression")));
+        assert_eq!(eval("HEIGHT", false), Err(String::from("HEIGHT is not a constant defined before this one")));
+        assert_eq!(eval("1 + \"a\"", false), Err(String::from("StringConst(\"a\") is not allowed in a constant expression")));
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use crate::source_map::*;
+use crate::symbol_table::*;
+use crate::vm::*;
+use crate::vm_writer::*;
+
+const HELP: &str = "\
+break Class.sub | File.jack:line   stop when the subroutine is called or the line is reached
+delete <n>                         remove breakpoint n
+continue                           run until a breakpoint or the end of the program
+step                               run to the next line, entering calls
+next                               run to the next line of this subroutine, or of its caller once it returns
+finish                             run until this subroutine returns
+backtrace                          list the calls in progress
+print [name[.field]...]            show a variable, or every argument and local
+quit";
+
+struct Breakpoint {
+    id: usize,
+    pcs: Vec<usize>, // instructions it stops at
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Resume {
+    Continue,
+    Step,
+    Next,
+    Finish,
+}
+
+/// Runs a compiled program on the VM emulator and reports its progress in terms of the Jack sources,
+/// using the `.vm.map` files written with the `.vm` ones. Functions without a map, such as those of the OS,
+/// are run through without stopping, except at a breakpoint set on their name.
+///
+/// Steps are by line: a line of source is where the commands located on it by the source map start.
+pub struct Debugger {
+    vm: Vm,
+    map: ProgramMap,
+    sources: Vec<Vec<String>>, // lines of the Jack file of each class of `map`
+    breakpoints: Vec<Breakpoint>,
+    stops: HashSet<usize>,     // instructions of all breakpoints
+    next_id: usize,
+}
+
+impl Debugger {
+    /// `maps` are the source maps of the classes of `program` with the text of their Jack source.
+    pub fn new(program: Program, maps: Vec<(SourceMap, String)>) -> Result<Debugger, String> {
+        let (maps, sources): (Vec<SourceMap>, Vec<String>) = maps.into_iter().unzip();
+        Ok(Debugger {
+            map: ProgramMap::new(&program, maps)?,
+            vm: Vm::new(program)?,
+            sources: sources.iter().map(|src| src.lines().map(String::from).collect()).collect(),
+            breakpoints: vec![],
+            stops: HashSet::new(),
+            next_id: 1,
+        })
+    }
+
+    /// Loads the .vm files of `dir` with their .vm.map files and the Jack sources these name, when present.
+    pub fn load(dir: &Path) -> Result<Debugger, String> {
+        let maps = SourceMap::load_dir(dir)?.into_iter()
+            .map(|map| {
+                let src = fs::read_to_string(dir.join(&map.file)).unwrap_or_default();
+                (map, src)
+            })
+            .collect();
+        Debugger::new(Program::load_dir(dir)?, maps)
+    }
+
+    /// Reads commands from `input` until it ends or `quit`.
+    pub fn repl(&mut self, input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        loop {
+            write!(out, "(jdb) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 || !self.execute(line.trim(), out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs one command, returning false for `quit`.
+    pub fn execute(&mut self, command: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let (name, arg) = command.split_once(' ').map_or((command, ""), |(c, a)| (c, a.trim()));
+        match name {
+            "" => (),
+            "break" | "b" => match self.add_breakpoint(arg) {
+                Ok(message) => writeln!(out, "{}", message)?,
+                Err(e) => writeln!(out, "{}", e)?,
+            },
+            "delete" | "d" => {
+                match arg.parse::<usize>().ok().and_then(|id| self.breakpoints.iter().position(|b| b.id == id)) {
+                    Some(i) => {
+                        self.breakpoints.remove(i);
+                        self.stops = self.breakpoints.iter().flat_map(|b| b.pcs.iter().copied()).collect();
+                    },
+                    None => writeln!(out, "no breakpoint {}", arg)?,
+                }
+            },
+            "continue" | "c" | "run" | "r" => self.resume(Resume::Continue, out)?,
+            "step" | "s" => self.resume(Resume::Step, out)?,
+            "next" | "n" => self.resume(Resume::Next, out)?,
+            "finish" => self.resume(Resume::Finish, out)?,
+            "backtrace" | "bt" => self.backtrace(out)?,
+            "print" | "p" => self.print(arg, out)?,
+            "help" | "h" => writeln!(out, "{}", HELP)?,
+            "quit" | "q" => { return Ok(false); },
+            _ => writeln!(out, "unknown command {}, try help", name)?,
+        }
+        Ok(true)
+    }
+
+    fn add_breakpoint(&mut self, spec: &str) -> Result<String, String> {
+        let (pcs, place) = match spec.rsplit_once(':') {
+            Some((file, line)) => {
+                let line = line.parse::<usize>().map_err(|_| format!("bad line number in {}", spec))?;
+                self.line_breakpoint(file, line)?
+            },
+            None => {
+                let f = self.vm.program.function_index(spec).ok_or_else(|| format!("no function {}", spec))?;
+                let function = &self.vm.program.functions[f];
+                let pc = (function.start..function.end).find(|pc| self.stoppable(*pc)).ok_or_else(|| format!("{} is empty", spec))?;
+                (vec![pc], spec.to_string())
+            },
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        self.stops.extend(pcs.iter().copied());
+        self.breakpoints.push(Breakpoint { id, pcs });
+        Ok(format!("Breakpoint {} at {}", id, place))
+    }
+
+    // The first instruction of each run of instructions located on `line`, or on the next line having some.
+    fn line_breakpoint(&self, file: &str, line: usize) -> Result<(Vec<usize>, String), String> {
+        let m = self.map.maps.iter().position(|map| map.file == file || Path::new(file).file_name().is_some_and(|f| f == map.file.as_str()))
+            .ok_or_else(|| format!("no source map for {}", file))?;
+        let lines: Vec<(usize, usize)> = (0..self.vm.program.code.len())
+            .filter(|pc| self.stoppable(*pc))
+            .filter_map(|pc| self.location(pc).filter(|(map, _)| *map == m).map(|(_, l)| (pc, l.line)))
+            .collect();
+        let line = lines.iter().map(|(_, l)| *l).filter(|l| *l >= line).min()
+            .ok_or_else(|| format!("no code at or after {}:{}", file, line))?;
+        let pcs = lines.iter().enumerate()
+            .filter(|(i, (_, l))| *l == line && (*i == 0 || lines[i - 1].1 != line))
+            .map(|(_, (pc, _))| *pc)
+            .collect();
+        Ok((pcs, format!("{}:{}", self.map.maps[m].file, line)))
+    }
+
+    // Instructions a step can end at: labels, jumps, function headers and the setup of `this` are skipped,
+    // the latter being located at the header by the source map.
+    fn stoppable(&self, pc: usize) -> bool {
+        if matches!(self.vm.program.code[pc], Instruction::Label | Instruction::Goto(_) | Instruction::Function(_)) {
+            return false;
+        }
+        let start = self.vm.program.functions[self.vm.program.function_at(pc)].start;
+        self.location(pc).is_none() || self.location(pc) != self.location(start)
+    }
+
+    // The source map and location of the instruction `pc`.
+    fn location(&self, pc: usize) -> Option<(usize, Location)> {
+        self.map.location(&self.vm.program, pc)
+    }
+
+    fn line(&self) -> Option<usize> {
+        self.location(self.vm.pc).map(|(_, l)| l.line)
+    }
+
+    fn resume(&mut self, mode: Resume, out: &mut dyn Write) -> io::Result<()> {
+        if self.vm.halted {
+            return writeln!(out, "the program has halted");
+        }
+        let depth = self.vm.frames.len();
+        let line = self.line();
+        loop {
+            if let Err(e) = self.vm.step() {
+                writeln!(out, "error: {}", e)?;
+                return self.where_(out);
+            }
+            if self.vm.halted {
+                return writeln!(out, "the program halted after {} instructions", self.vm.steps);
+            }
+            let pc = self.vm.pc;
+            if !self.stoppable(pc) {
+                continue;
+            }
+            if self.stops.contains(&pc) {
+                let id = self.breakpoints.iter().find(|b| b.pcs.contains(&pc)).map_or(0, |b| b.id);
+                write!(out, "Breakpoint {}, ", id)?;
+                return self.where_(out);
+            }
+            let here = self.vm.frames.len();
+            let located = self.location(pc).is_some();
+            let stop = match mode {
+                Resume::Continue => false,
+                Resume::Step     => located && (here != depth || self.line() != line),
+                Resume::Next     => located && (here < depth || (here == depth && self.line() != line)),
+                Resume::Finish   => here < depth,
+            };
+            if stop {
+                if mode == Resume::Finish {
+                    let top = self.vm.ram[SP] as u16 as usize;
+                    writeln!(out, "returned {}", self.vm.ram[top.saturating_sub(1)])?;
+                }
+                return self.where_(out);
+            }
+        }
+    }
+
+    // Prints the current location with its line of source.
+    fn where_(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{}", self.describe(self.vm.pc))?;
+        if let Some((m, l)) = self.location(self.vm.pc) {
+            writeln!(out, "{}\t{}", l.line, self.sources[m].get(l.line - 1).map_or("", |s| s.as_str()))?;
+        }
+        Ok(())
+    }
+
+    fn describe(&self, pc: usize) -> String {
+        let function = &self.vm.program.functions[self.vm.program.function_at(pc)];
+        match self.location(pc) {
+            Some((m, l)) => format!("{} at {}:{}", function.name, self.map.maps[m].file, l.line),
+            None => function.name.clone(),
+        }
+    }
+
+    fn backtrace(&self, out: &mut dyn Write) -> io::Result<()> {
+        let frames = &self.vm.frames;
+        for i in (0..frames.len()).rev() {
+            // callers are at their call instruction
+            let pc = frames.get(i + 1).map_or(self.vm.pc, |callee| callee.return_pc - 1);
+            writeln!(out, "#{}  {}", frames.len() - 1 - i, self.describe(pc))?;
+        }
+        Ok(())
+    }
+
+    fn print(&self, path: &str, out: &mut dyn Write) -> io::Result<()> {
+        let f = self.vm.frames.last().map(|frame| frame.function);
+        let (m, function) = match f.and_then(|f| self.map.function(f)) {
+            Some(map) => map,
+            None => { return writeln!(out, "no source for {}", self.vm.function().name); }
+        };
+        if path.is_empty() {
+            if function.variables.is_empty() {
+                writeln!(out, "no variables")?;
+            }
+            for v in function.variables.iter() {
+                writeln!(out, "{} = {}", v.name, self.value(v))?;
+            }
+            return Ok(());
+        }
+        let mut names = path.split('.');
+        let name = names.next().unwrap_or_default();
+        let var = function.variables.iter().chain(self.map.maps[m].variables.iter()).find(|v| v.name == name);
+        let mut var = match (var, name) {
+            (Some(v), _) => v.clone(),
+            (None, "this") => Variable { name: String::from("this"), kind: VarKind::Arg, var_type: self.class_of(m), index: usize::MAX },
+            (None, _) => { return writeln!(out, "no variable {} in {}", name, function.name); }
+        };
+        let mut address = self.address(&var);
+        for field in names {
+            let object = match address {
+                Some(a) => self.vm.ram[a],
+                None => { return writeln!(out, "{} is outside the memory", var.name); }
+            };
+            let class = self.map.maps.iter().find(|map| map.file.strip_suffix(".jack") == Some(var.var_type.as_str()));
+            let member = class.and_then(|c| c.variables.iter().find(|v| v.name == field && v.kind == VarKind::Field));
+            var = match (member, object) {
+                (Some(v), 1..) => v.clone(),
+                (Some(_), _) => { return writeln!(out, "{} is null", var.name); },
+                (None, _) => { return writeln!(out, "no field {} in {}", field, var.var_type); },
+            };
+            address = Some(object as usize + var.index).filter(|a| *a < RAM_SIZE);
+        }
+        match address {
+            Some(a) => writeln!(out, "{} = {}", path, format_value(self.vm.ram[a], &var.var_type)),
+            None => writeln!(out, "{} is outside the memory", path),
+        }
+    }
+
+    fn class_of(&self, m: usize) -> String {
+        self.map.maps[m].file.trim_end_matches(".jack").to_string()
+    }
+
+    fn value(&self, var: &Variable) -> String {
+        match self.address(var) {
+            Some(a) => format_value(self.vm.ram[a], &var.var_type),
+            None => String::from("?"),
+        }
+    }
+
+    // The address of a variable of the current frame; index usize::MAX stands for `this`.
+    fn address(&self, var: &Variable) -> Option<usize> {
+        let (segment, index) = match var.kind {
+            _ if var.index == usize::MAX => (Segment::Pointer, 0),
+            VarKind::Static => (Segment::Static, var.index),
+            VarKind::Field  => (Segment::This, var.index),
+            VarKind::Arg    => (Segment::Arg, var.index),
+            VarKind::Var    => (Segment::Local, var.index),
+            VarKind::Const  => { return None; }
+        };
+        self.vm.address(segment, index as u16).ok()
+    }
+}
+
+fn format_value(value: i16, var_type: &str) -> String {
+    match (var_type, value) {
+        ("int", v) => v.to_string(),
+        ("boolean", 0) => String::from("false"),
+        ("boolean", -1) => String::from("true"),
+        ("char", c @ 32..=126) => format!("{} '{}'", c, c as u8 as char),
+        ("boolean" | "char", v) => v.to_string(),
+        (_, 0) => String::from("null"),
+        (class, v) => format!("{}@{}", class, v as u16),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::*;
+    use std::env;
+
+    fn debug(name: &str, src: &str, commands: &str) -> String {
+        let dir = env::temp_dir().join(format!("jackc_debug_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Main.jack"), src).unwrap();
+        for os in ["Sys", "Memory", "Math", "Screen", "Output", "Keyboard", "String", "Array"] {
+            fs::copy(format!("./tests/Seven/{}.vm", os), dir.join(format!("{}.vm", os))).unwrap();
+        }
+        let mut compiler = Compiler::default();
+        compiler.set_emit(vec![Emit::Map]);
+        assert!(compiler.run(&dir.join("Main.jack")));
+        let mut d = Debugger::load(&dir).unwrap();
+        let mut out = vec![];
+        d.repl(&mut commands.as_bytes(), &mut out).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        String::from_utf8(out).unwrap().replace("(jdb) ", "")
+    }
+
+    #[test]
+    fn test_debugger() {
+        let src = "\
+class Main {
+    static int total;
+    field int size;
+
+    function void main() {
+        var Main m;
+        let m = Main.new(3);
+        let total = m.sum(2);
+        return;
+    }
+
+    constructor Main new(int n) {
+        let size = n;
+        return this;
+    }
+
+    method int sum(int k) {
+        var int i, s;
+        while (i < size) {
+            let s = s + k;
+            let i = i + 1;
+        }
+        return s;
+    }
+}
+";
+        let commands = "break Main.sum\nbreak Main.jack:21\ncontinue\nbacktrace\nprint\nprint this.size\ndelete 1\n\
+                        continue\nprint s\nnext\nnext\ndelete 2\nfinish\nnext\nprint total\nprint m\nprint n\ncontinue\nstep\n";
+        let expected = "\
+Breakpoint 1 at Main.sum
+Breakpoint 2 at Main.jack:21
+Breakpoint 1, Main.sum at Main.jack:19
+19\t        while (i < size) {
+#0  Main.sum at Main.jack:19
+#1  Main.main at Main.jack:8
+#2  Sys.init
+k = 2
+i = 0
+s = 0
+this.size = 3
+Breakpoint 2, Main.sum at Main.jack:21
+21\t            let i = i + 1;
+s = 2
+Main.sum at Main.jack:19
+19\t        while (i < size) {
+Main.sum at Main.jack:20
+20\t            let s = s + k;
+returned 6
+Main.main at Main.jack:8
+8\t        let total = m.sum(2);
+Main.main at Main.jack:9
+9\t        return;
+total = 6
+m = Main@4872
+no variable n in Main.main
+the program halted after 1199051 instructions
+the program has halted
+";
+        assert_eq!(debug("run", src, commands), expected);
+    }
+
+    #[test]
+    fn test_print_field_outside_memory() {
+        let src = "\
+class Main {
+    field int size, count;
+
+    function void main() {
+        var Main bad;
+        let bad = 32767;
+        return;
+    }
+}
+";
+        let output = debug("outside", src, "break Main.jack:7\ncontinue\nprint bad.size\nprint bad.count\n");
+        assert!(output.ends_with("7\t        return;\nbad.size = 0\nbad.count is outside the memory\n"), "{}", output);
+    }
+}
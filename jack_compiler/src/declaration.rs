@@ -0,0 +1,344 @@
+use std::fmt;
+use crate::tokenizer::*;
+use crate::keyword::*;
+use crate::symbol::*;
+use crate::symbol_table::*;
+use crate::diagnostic::*;
+use crate::options::*;
+use crate::constant;
+
+/// A static, field or constant declared at class level, or a parameter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VarDecl {
+    pub name: String,
+    pub kind: VarKind,
+    pub var_type: VarType,
+    pub span: Span,
+    pub doc: Option<String>,
+    pub value: Option<i16>, // of a constant whose expression could be evaluated
+    pub init: Vec<Token>,   // the expression of a constant
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubroutineDecl {
+    pub name: String,
+    pub kind: Keyword, // Constructor, Function or Method
+    pub return_type: Option<VarType>, // None for void
+    pub params: Vec<VarDecl>,
+    pub span: Span,  // subroutine name
+    pub range: Span, // from the leading keyword to the closing brace of the body
+    pub doc: Option<String>,
+}
+
+impl SubroutineDecl {
+    pub fn signature(&self) -> String {
+        let params: Vec<String> = self.params.iter().map(|p| format!("{} {}", p.var_type, p.name)).collect();
+        let ret = match &self.return_type {
+            Some(t) => t.to_string(),
+            None => String::from("void"),
+        };
+        format!("{} {} {}({})", self.kind, ret, self.name, params.join(", "))
+    }
+}
+
+/// The outline of a class: its name, class-level variables and subroutine signatures, without bodies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClassDecl {
+    pub name: String,
+    pub span: Span,
+    pub range: Span,
+    pub vars: Vec<VarDecl>,
+    pub subroutines: Vec<SubroutineDecl>,
+    pub doc: Option<String>,
+}
+
+impl ClassDecl {
+    /// Reads the outline of the class from the remaining tokens of `t` without consuming them.
+    /// Parsing is tolerant: subroutine bodies are skipped by brace matching and anything unexpected
+    /// ends the outline there, so an incomplete file still yields what was declared before the error.
+    pub fn parse(t: &Tokenizer) -> Option<ClassDecl> {
+        ClassDecl::parse_with(t, &Options::default())
+    }
+
+    /// Same as `parse`, evaluating the constants with the operator grouping selected by `options`.
+    pub fn parse_with(t: &Tokenizer, options: &Options) -> Option<ClassDecl> {
+        let toks: Vec<(&Token, &Span)> = t.source_tokens().collect();
+        let mut p = OutlineParser { tokenizer: t, toks: &toks, pos: 0, precedence: options.precedence, closed: false };
+        p.parse_class()
+    }
+
+    /// Reads every class of a declaration file such as a stub of the OS API, where subroutines may end with `;`
+    /// instead of a body. Unlike `parse`, anything that is not a complete class declaration is an error.
+    pub fn parse_all(t: &Tokenizer, options: &Options) -> Result<Vec<ClassDecl>, Diagnostic> {
+        let toks: Vec<(&Token, &Span)> = t.source_tokens().collect();
+        let mut p = OutlineParser { tokenizer: t, toks: &toks, pos: 0, precedence: options.precedence, closed: false };
+        let mut classes = vec![];
+        while p.pos < toks.len() {
+            match p.parse_class() {
+                Some(c) if p.closed => classes.push(c),
+                _ => {
+                    let span = toks.get(p.pos.min(toks.len()).saturating_sub(1)).map(|(_, s)| **s);
+                    return Err(Diagnostic::error(span, String::from("incomplete class declaration")));
+                }
+            }
+        }
+        Ok(classes)
+    }
+
+    pub fn subroutine(&self, name: &str) -> Option<&SubroutineDecl> {
+        self.subroutines.iter().find(|s| s.name == name)
+    }
+
+    pub fn var(&self, name: &str) -> Option<&VarDecl> {
+        self.vars.iter().find(|v| v.name == name)
+    }
+}
+
+impl fmt::Display for ClassDecl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "class {} {{", self.name)?;
+        for v in self.vars.iter() {
+            match v.value {
+                Some(value) => writeln!(f, "    {} {} {} = {};", v.kind, v.var_type, v.name, value)?,
+                None => writeln!(f, "    {} {} {};", v.kind, v.var_type, v.name)?,
+            }
+        }
+        for s in self.subroutines.iter() {
+            writeln!(f, "    {};", s.signature())?;
+        }
+        write!(f, "}}")
+    }
+}
+
+struct OutlineParser<'a> {
+    tokenizer: &'a Tokenizer,
+    toks: &'a [(&'a Token, &'a Span)],
+    pos: usize,
+    precedence: bool,
+    closed: bool, // the last class parsed ended with its closing brace
+}
+
+impl<'a> OutlineParser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.toks.get(self.pos).map(|(t, _)| *t)
+    }
+
+    fn next(&mut self) -> Option<(&'a Token, Span)> {
+        let t = self.toks.get(self.pos).map(|(t, s)| (*t, **s));
+        self.pos += 1;
+        t
+    }
+
+    fn doc(&self, span: &Span) -> Option<String> {
+        self.tokenizer.doc_comment(span).map(|d| d.to_string())
+    }
+
+    fn identifier(&mut self) -> Option<(String, Span)> {
+        match self.next()? {
+            (Token::Identifier(i), s) => Some((i.clone(), s)),
+            _ => None,
+        }
+    }
+
+    fn symbol(&mut self, sym: Symbol) -> Option<Span> {
+        match self.next()? {
+            (Token::Symbol(s), span) if *s == sym => Some(span),
+            _ => None,
+        }
+    }
+
+    fn var_type(&mut self) -> Option<VarType> {
+        match self.next()? {
+            (Token::Keyword(Keyword::Int), _)     => Some(VarType::Int),
+            (Token::Keyword(Keyword::Char), _)    => Some(VarType::Char),
+            (Token::Keyword(Keyword::Boolean), _) => Some(VarType::Boolean),
+            (Token::Identifier(c), _)             => Some(VarType::ClassName(c.clone())),
+            _ => None,
+        }
+    }
+
+    fn parse_class(&mut self) -> Option<ClassDecl> {
+        let start = match self.next()? {
+            (Token::Keyword(Keyword::Class), s) => s,
+            _ => { return None; }
+        };
+        self.closed = false;
+        let (name, span) = self.identifier()?;
+        let mut class = ClassDecl {
+            name,
+            span,
+            range: Span { end: span.end, ..start },
+            vars: vec![],
+            subroutines: vec![],
+            doc: self.doc(&start),
+        };
+        if self.symbol(Symbol::BraceL).is_none() {
+            return Some(class);
+        }
+        while let Some(t) = self.peek() {
+            let parsed = match t {
+                Token::Keyword(Keyword::Static | Keyword::Field) => self.parse_class_var_dec(&mut class.vars),
+                Token::Keyword(Keyword::Const) => self.parse_const_dec(&class.name, &mut class.vars),
+                Token::Keyword(Keyword::Constructor | Keyword::Function | Keyword::Method) => {
+                    self.parse_subroutine_dec().map(|s| class.subroutines.push(s))
+                },
+                Token::Symbol(Symbol::BraceR) => {
+                    class.range.end = self.next().unwrap().1.end;
+                    self.closed = true;
+                    break;
+                },
+                _ => None,
+            };
+            if parsed.is_none() {
+                break;
+            }
+        }
+        if let Some((_, s)) = self.toks.get(self.pos.min(self.toks.len()).saturating_sub(1)) {
+            class.range.end = class.range.end.max(s.end);
+        }
+        Some(class)
+    }
+
+    fn parse_class_var_dec(&mut self, vars: &mut Vec<VarDecl>) -> Option<()> {
+        let (kind, doc) = match self.next()? {
+            (Token::Keyword(Keyword::Static), s) => (VarKind::Static, self.doc(&s)),
+            (_, s) => (VarKind::Field, self.doc(&s)),
+        };
+        let var_type = self.var_type()?;
+        loop {
+            let (name, span) = self.identifier()?;
+            vars.push(VarDecl { name, kind, var_type: var_type.clone(), span, doc: doc.clone(), value: None, init: vec![] });
+            match self.next()? {
+                (Token::Symbol(Symbol::Comma), _) => (),
+                (Token::Symbol(Symbol::SemiColon), _) => { return Some(()); },
+                _ => { return None; }
+            }
+        }
+    }
+
+    // 'const' type varName '=' expression ';'
+    // The value is left unknown when the expression names constants of other classes, see `Registry::resolve_constants`.
+    fn parse_const_dec(&mut self, class_name: &str, vars: &mut Vec<VarDecl>) -> Option<()> {
+        let doc = self.next().and_then(|(_, s)| self.doc(&s));
+        let var_type = self.var_type()?;
+        let (name, span) = self.identifier()?;
+        self.symbol(Symbol::Equal)?;
+        let mut tokens = vec![];
+        while self.peek()? != &Token::Symbol(Symbol::SemiColon) {
+            tokens.push(self.next()?.0.clone());
+        }
+        self.next();
+        let defined = |n: &str| {
+            let n = n.strip_prefix(class_name).and_then(|n| n.strip_prefix('.')).unwrap_or(n);
+            vars.iter().find(|v| v.kind == VarKind::Const && v.name == n).and_then(|v| v.value)
+        };
+        let value = constant::evaluate(&tokens, self.precedence, &defined).ok();
+        vars.push(VarDecl { name, kind: VarKind::Const, var_type, span, doc, value, init: tokens });
+        Some(())
+    }
+
+    fn parse_subroutine_dec(&mut self) -> Option<SubroutineDecl> {
+        let (kind, start) = match self.next()? {
+            (Token::Keyword(kw), s) => (*kw, s),
+            _ => { return None; }
+        };
+        let return_type = match self.peek()? {
+            Token::Keyword(Keyword::Void) => {
+                self.next();
+                None
+            },
+            _ => Some(self.var_type()?),
+        };
+        let (name, span) = self.identifier()?;
+        let mut sub = SubroutineDecl {
+            name,
+            kind,
+            return_type,
+            params: vec![],
+            span,
+            range: Span { end: span.end, ..start },
+            doc: self.doc(&start),
+        };
+        self.symbol(Symbol::ParenL)?;
+        if self.peek() != Some(&Token::Symbol(Symbol::ParenR)) {
+            loop {
+                let var_type = self.var_type()?;
+                let (name, span) = self.identifier()?;
+                sub.params.push(VarDecl { name, kind: VarKind::Arg, var_type, span, doc: None, value: None, init: vec![] });
+                match self.peek()? {
+                    Token::Symbol(Symbol::Comma) => { self.next(); },
+                    _ => { break; }
+                }
+            }
+        }
+        self.symbol(Symbol::ParenR)?;
+        // ';' in a declaration file
+        if self.peek() == Some(&Token::Symbol(Symbol::SemiColon)) {
+            sub.range.end = self.next()?.1.end;
+            return Some(sub);
+        }
+        // subroutineBody: skip to the matching '}'
+        sub.range.end = self.symbol(Symbol::BraceL)?.end;
+        let mut depth = 1;
+        while depth > 0 {
+            let (t, s) = self.next()?;
+            match t {
+                Token::Symbol(Symbol::BraceL) => { depth += 1; },
+                Token::Symbol(Symbol::BraceR) => { depth -= 1; },
+                _ => (),
+            }
+            sub.range.end = s.end;
+        }
+        Some(sub)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_outline() {
+        let src = "class Foo {\n  field int x, y;\n  static Foo instance;\n\
+                   \n  constructor Foo new(int ax, Bar b) { let x = ax; if (x) { return this; } return this; }\n\
+                   \n  method void run() { return; }\n}\n";
+        let c = ClassDecl::parse(&Tokenizer::new(src.as_bytes())).unwrap();
+        assert_eq!(c.name, "Foo");
+        assert_eq!(c.vars.len(), 3);
+        assert_eq!(c.var("instance").unwrap().var_type, VarType::ClassName(String::from("Foo")));
+        assert_eq!(c.subroutines.len(), 2);
+        assert_eq!(c.subroutine("new").unwrap().signature(), "constructor Foo new(int ax, Bar b)");
+        assert_eq!(c.subroutine("run").unwrap().span.line, 7);
+        assert_eq!(c.range.end, src.trim_end().len());
+    }
+
+    #[test]
+    fn test_parse_constants() {
+        let src = "class Foo {\n  const int A = 2 + 3 * 4;\n  const char B = Foo.A + 'a';\n  const int C = Bar.X;\n}\n";
+        let prec = Options { extensions: true, precedence: true, ..Options::default() };
+        let c = ClassDecl::parse_with(&Tokenizer::with_options(src.as_bytes(), &prec), &prec).unwrap();
+        let values: Vec<Option<i16>> = c.vars.iter().map(|v| v.value).collect();
+        assert_eq!(values, vec![Some(14), Some(111), None]);
+        assert_eq!(c.var("C").unwrap().init, vec![Token::Identifier(String::from("Bar")), Token::Symbol(Symbol::Dot), Token::Identifier(String::from("X"))]);
+        assert_eq!(c.to_string(), "class Foo {\n    const int A = 14;\n    const char B = 111;\n    const int C;\n}");
+    }
+
+    #[test]
+    fn test_parse_declaration_file() {
+        let src = "class Math {\n  function int abs(int x);\n  function int max(int a, int b);\n}\nclass Point {\n  field int x;\n  method int getX();\n}\n";
+        let classes = ClassDecl::parse_all(&Tokenizer::new(src.as_bytes()), &Options::default()).unwrap();
+        assert_eq!(classes.len(), 2);
+        assert_eq!(classes[0].subroutine("max").unwrap().signature(), "function int max(int a, int b)");
+        assert_eq!(classes[1].to_string(), "class Point {\n    field int x;\n    method int getX();\n}");
+        let err = ClassDecl::parse_all(&Tokenizer::new("class Math {\n  function abs(int x);\n}".as_bytes()), &Options::default()).unwrap_err();
+        assert_eq!((err.span.unwrap().line, err.message.as_str()), (2, "incomplete class declaration"));
+    }
+
+    #[test]
+    fn test_parse_incomplete_outline() {
+        let src = "class Foo {\n  method void run() { return; }\n  function int broken( {";
+        let c = ClassDecl::parse(&Tokenizer::new(src.as_bytes())).unwrap();
+        assert_eq!(c.subroutines.len(), 1);
+        assert_eq!(c.subroutines[0].name, "run");
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt;
+use crate::tokenizer::Span;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error   => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A message about a source file, printed as `file:line:col: severity: message`,
+/// followed by one `file:line:col: note: message` line per related location.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub span: Option<Span>,
+    pub severity: Severity,
+    pub message: String,
+    pub notes: Vec<(Span, String)>, // other places in the same file the message refers to
+}
+
+impl Diagnostic {
+    pub fn error(span: Option<Span>, message: String) -> Self {
+        Diagnostic {
+            file: String::new(),
+            span,
+            severity: Severity::Error,
+            message,
+            notes: vec![],
+        }
+    }
+
+    pub fn warning(span: Option<Span>, message: String) -> Self {
+        Diagnostic {
+            file: String::new(),
+            span,
+            severity: Severity::Warning,
+            message,
+            notes: vec![],
+        }
+    }
+
+    pub fn with_note(mut self, span: Span, message: String) -> Self {
+        self.notes.push((span, message));
+        self
+    }
+
+    pub fn in_file(mut self, file: &str) -> Self {
+        self.file = file.to_string();
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}:{}:{}: {}: {}", self.file, span.line, span.col, self.severity, self.message)?,
+            None       => write!(f, "{}: {}: {}", self.file, self.severity, self.message)?,
+        }
+        for (span, message) in self.notes.iter() {
+            write!(f, "\n{}:{}:{}: note: {}", self.file, span.line, span.col, message)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::declaration::*;
+use crate::keyword::*;
+
+#[derive(Debug, Clone)]
+pub struct BadDocFormatError;
+
+impl fmt::Display for BadDocFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bad doc format, expected md or html")
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DocFormat {
+    Markdown,
+    Html,
+}
+
+impl DocFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DocFormat::Markdown => "md",
+            DocFormat::Html     => "html",
+        }
+    }
+
+    pub fn render(&self, class: &ClassDecl) -> String {
+        match self {
+            DocFormat::Markdown => markdown(class),
+            DocFormat::Html     => html(class),
+        }
+    }
+}
+
+impl FromStr for DocFormat {
+    type Err = BadDocFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md" | "markdown" => Ok(DocFormat::Markdown),
+            "html"            => Ok(DocFormat::Html),
+            _                 => Err(BadDocFormatError),
+        }
+    }
+}
+
+// subroutines grouped the way the reference lists them
+fn sections(class: &ClassDecl) -> Vec<(&'static str, Vec<&SubroutineDecl>)> {
+    [(Keyword::Constructor, "Constructors"), (Keyword::Function, "Functions"), (Keyword::Method, "Methods")]
+        .iter()
+        .map(|(kw, title)| (*title, class.subroutines.iter().filter(|s| s.kind == *kw).collect::<Vec<_>>()))
+        .filter(|(_, subs)| !subs.is_empty())
+        .collect()
+}
+
+/// API reference of a class in Markdown: description, class variables, then subroutines by kind.
+pub fn markdown(class: &ClassDecl) -> String {
+    let mut s = format!("# class {}\n\n", class.name);
+    if let Some(doc) = &class.doc {
+        s += &format!("{}\n\n", doc);
+    }
+    if !class.vars.is_empty() {
+        s += "## Fields\n\n| Kind | Type | Name | Description |\n| --- | --- | --- | --- |\n";
+        for v in class.vars.iter() {
+            let doc = v.doc.as_deref().unwrap_or("").replace('\n', " ").replace('|', "\\|");
+            s += &format!("| {} | `{}` | `{}` | {} |\n", v.kind, v.var_type, v.name, doc);
+        }
+        s += "\n";
+    }
+    for (title, subs) in sections(class) {
+        s += &format!("## {}\n\n", title);
+        for sub in subs {
+            s += &format!("### {}\n\n```jack\n{}\n```\n\n", sub.name, sub.signature());
+            if let Some(doc) = &sub.doc {
+                s += &format!("{}\n\n", doc);
+            }
+        }
+    }
+    s.truncate(s.trim_end().len());
+    s.push('\n');
+    s
+}
+
+/// The same reference as `markdown`, as a standalone HTML page.
+pub fn html(class: &ClassDecl) -> String {
+    let mut s = format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>class {0}</title></head>\n<body>\n<h1>class {0}</h1>\n", escape(&class.name));
+    if let Some(doc) = &class.doc {
+        s += &paragraphs(doc);
+    }
+    if !class.vars.is_empty() {
+        s += "<h2>Fields</h2>\n<table>\n<tr><th>Kind</th><th>Type</th><th>Name</th><th>Description</th></tr>\n";
+        for v in class.vars.iter() {
+            s += &format!("<tr><td>{}</td><td><code>{}</code></td><td><code>{}</code></td><td>{}</td></tr>\n",
+                v.kind, escape(&v.var_type.to_string()), escape(&v.name), escape(v.doc.as_deref().unwrap_or("")));
+        }
+        s += "</table>\n";
+    }
+    for (title, subs) in sections(class) {
+        s += &format!("<h2>{}</h2>\n", title);
+        for sub in subs {
+            s += &format!("<h3 id=\"{0}\">{0}</h3>\n<pre><code>{1}</code></pre>\n", escape(&sub.name), escape(&sub.signature()));
+            if let Some(doc) = &sub.doc {
+                s += &paragraphs(doc);
+            }
+        }
+    }
+    s += "</body>\n</html>\n";
+    s
+}
+
+fn paragraphs(doc: &str) -> String {
+    doc.split("\n\n")
+        .map(|p| format!("<p>{}</p>\n", escape(p.trim())))
+        .collect()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::*;
+
+    const SRC: &str = "/** A point.\n *\n * Immutable. */\nclass Point {\n    /** horizontal <coordinate> */\n    field int x;\n    field boolean visible;\n\n    /** Creates\n     * a point. */\n    constructor Point new(int ax) { let x = ax; return this; }\n\n    // not a doc comment\n    method int getX() { return x; }\n}\n";
+
+    #[test]
+    fn test_doc_comments_attached() {
+        let c = ClassDecl::parse(&Tokenizer::new(SRC.as_bytes())).unwrap();
+        assert_eq!(c.doc.as_deref(), Some("A point.\n\nImmutable."));
+        assert_eq!(c.var("x").unwrap().doc.as_deref(), Some("horizontal <coordinate>"));
+        assert_eq!(c.var("visible").unwrap().doc, None);
+        assert_eq!(c.subroutine("new").unwrap().doc.as_deref(), Some("Creates\na point."));
+        assert_eq!(c.subroutine("getX").unwrap().doc, None);
+    }
+
+    #[test]
+    fn test_markdown() {
+        let c = ClassDecl::parse(&Tokenizer::new(SRC.as_bytes())).unwrap();
+        assert_eq!(markdown(&c), "# class Point\n\nA point.\n\nImmutable.\n\n## Fields\n\n\
+            | Kind | Type | Name | Description |\n| --- | --- | --- | --- |\n\
+            | field | `int` | `x` | horizontal <coordinate> |\n\
+            | field | `boolean` | `visible` |  |\n\n\
+            ## Constructors\n\n### new\n\n```jack\nconstructor Point new(int ax)\n```\n\nCreates\na point.\n\n\
+            ## Methods\n\n### getX\n\n```jack\nmethod int getX()\n```\n");
+    }
+
+    #[test]
+    fn test_html_escapes() {
+        let c = ClassDecl::parse(&Tokenizer::new(SRC.as_bytes())).unwrap();
+        let page = html(&c);
+        assert!(page.contains("<td>horizontal &lt;coordinate&gt;</td>"));
+        assert!(page.contains("<p>A point.</p>\n<p>Immutable.</p>\n"));
+    }
+}
@@ -1,28 +1,72 @@
-use std::fs::File;
+use std::io::Write;
 use crate::tokenizer::*;
 use crate::keyword::*;
 use crate::symbol::*;
 use crate::symbol_table::*;
 use crate::vm_writer::*;
+use crate::declaration::*;
+use crate::diagnostic::*;
+use crate::lint::*;
+use crate::options::*;
+use crate::constant;
+use crate::registry::*;
+
+/// A subroutine compiled so far, with the index in `Engine::symbol_refs` where its own references start.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubroutineRefs {
+    pub kind: Keyword,
+    pub name: String, // Class.name
+    pub refs_start: usize,
+}
 
 pub struct Engine {
     tokenizer: Tokenizer,
+    options: Options,
     sym_tbl: SymbolTable,
     vm_writer: VMWriter,
     class_name: String,
     if_count: usize,
     while_count: usize,
+    logic_count: usize,
+    loops: Vec<(String, String)>, // continue and break labels of the enclosing loops, innermost last
+    refs: Vec<SymbolRef>,
+    class_decl: Option<ClassDecl>,
+    subroutine_kind: Keyword,
+    sub_refs_start: usize,
+    annotated_line: usize, // last source line written as a comment
+    subroutines: Vec<SubroutineRefs>,
+    lints: Option<(LintConfig, Suppressions)>,
+    diagnostics: Vec<Diagnostic>,
+    registry: Registry, // the other classes of the program
 }
 
 impl Engine {
-    pub fn new(t: Tokenizer, f: File) -> Self {
+    pub fn new<W: Write + 'static>(t: Tokenizer, f: W) -> Self {
+        Engine::with_options(t, f, Options::default())
+    }
+
+    pub fn with_options<W: Write + 'static>(t: Tokenizer, f: W, options: Options) -> Self {
+        let mut vm_writer = VMWriter::new(f);
+        vm_writer.set_annotate(options.annotate);
         Engine {
             tokenizer: t,
+            options,
             sym_tbl: SymbolTable::new(),
-            vm_writer: VMWriter::new(f),
+            vm_writer,
             class_name: String::new(),
             if_count: 0,
             while_count: 0,
+            logic_count: 0,
+            loops: vec![],
+            refs: vec![],
+            class_decl: None,
+            subroutine_kind: Keyword::Function,
+            sub_refs_start: 0,
+            annotated_line: 0,
+            subroutines: vec![],
+            lints: None,
+            diagnostics: vec![],
+            registry: Registry::new(),
         }
     }
     
@@ -31,17 +75,67 @@ impl Engine {
         self.vm_writer.close();
     }
 
+    /// Location of the token the engine is currently looking at, used to place error messages.
+    pub fn current_span(&self) -> Option<Span> {
+        self.tokenizer.current_span()
+    }
+
+    /// Every declaration and use of a variable seen so far, in source order.
+    pub fn symbol_refs(&self) -> &[SymbolRef] {
+        &self.refs
+    }
+
+    pub fn class_name(&self) -> &str {
+        &self.class_name
+    }
+
+    /// The subroutines compiled so far, in source order.
+    pub fn subroutines(&self) -> &[SubroutineRefs] {
+        &self.subroutines
+    }
+
+    /// The source locations of the VM functions written so far, see `VMWriter::locations`.
+    pub fn vm_locations(&self) -> &[(String, Vec<Span>)] {
+        self.vm_writer.locations()
+    }
+
+    /// Checks the rules enabled in `config` while compiling, reporting them as warnings.
+    pub fn enable_lints(&mut self, config: LintConfig) {
+        let class = ClassDecl::parse(&self.tokenizer);
+        let suppressions = Suppressions::from_comments(&self.tokenizer.comments, class.as_ref());
+        self.lints = Some((config, suppressions));
+    }
+
+    /// Lets the engine check calls to the classes of `registry` and use their constants as `Class.NAME`.
+    /// Without one only the class being compiled is known.
+    pub fn set_registry(&mut self, registry: Registry) {
+        self.registry = registry;
+    }
+
+    /// Warnings, and errors that do not stop the compilation such as duplicate declarations, found so far.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
     fn compile_class(&mut self) {
+        self.class_decl = ClassDecl::parse_with(&self.tokenizer, &self.options);
         // 'class' className '{'
         self.compile_keyword_expect(Keyword::Class);
         self.class_name = self.compile_class_name();
+        if !is_upper_camel_case(&self.class_name) {
+            let span = self.span();
+            self.lint(Lint::Naming, span, format!("class name {} should be UpperCamelCase", self.class_name));
+        }
         self.compile_symbol_expect(Symbol::BraceL);
         // classVarDec*
         'classVarDec: loop {
             match self.tokenizer.peek_next_token().unwrap() {
-                &Token::Keyword(Keyword::Static | Keyword::Field) => {
+                Token::Keyword(Keyword::Static | Keyword::Field) => {
                     self.compile_class_var_dec();
                 },
+                Token::Keyword(Keyword::Const) => {
+                    self.compile_const_dec();
+                },
                 _ => {
                     break 'classVarDec;
                 }
@@ -60,6 +154,7 @@ impl Engine {
         }
         // '}'
         self.compile_symbol_expect(Symbol::BraceR);
+        self.lint_unused(0, &[VarKind::Static, VarKind::Field]);
     }
 
     fn compile_class_var_dec(&mut self) {
@@ -94,8 +189,38 @@ impl Engine {
         self.compile_symbol_expect(Symbol::SemiColon);
     }
     
+    fn compile_const_dec(&mut self) {
+        // 'const' type varName '='
+        self.compile_keyword_expect(Keyword::Const);
+        let vartype = self.compile_type().unwrap();
+        if let VarType::ClassName(_) = vartype {
+            panic!("constant type must be int, char or boolean, found {}", vartype);
+        }
+        let name = self.compile_var_name_defined(VarKind::Const, vartype);
+        self.compile_symbol_expect(Symbol::Equal);
+        // expression ';', evaluated here
+        let mut tokens = vec![];
+        while !matches!(self.tokenizer.peek_next_token(), Some(Token::Symbol(Symbol::SemiColon)) | None) {
+            tokens.push(self.next_token());
+        }
+        let value = constant::evaluate(&tokens, self.options.precedence, &|name| self.constant_value(name))
+            .unwrap_or_else(|e| panic!("{}", self.registry.constant_error(&self.class_name, &name).unwrap_or(&e)));
+        self.sym_tbl.set_value(&name, value);
+        self.compile_symbol_expect(Symbol::SemiColon);
+    }
+
+    // The value of a constant of this class named `NAME` or `Class.NAME`, or of another class named `Class.NAME`.
+    fn constant_value(&self, name: &str) -> Option<i16> {
+        match name.split_once('.') {
+            Some((class, member)) if class == self.class_name => self.sym_tbl.value_of(member),
+            Some((class, member)) => self.registry.constant(class, member),
+            None => self.sym_tbl.value_of(name),
+        }
+    }
+
     fn compile_subroutine_dec(&mut self) {
         self.sym_tbl.start_subroutine();
+        self.sub_refs_start = self.refs.len();
         // 'constructor' | 'function' | 'method'
         let subroutine_type = match self.tokenizer.peek_next_token().unwrap() {
             Token::Keyword(Keyword::Constructor | Keyword::Function | Keyword::Method) => {
@@ -105,6 +230,7 @@ impl Engine {
                 panic!("'constructor', 'function' or 'method' expected, found {:?}", t);
             }
         };
+        self.subroutine_kind = subroutine_type;
         // 'void' | type
         match self.tokenizer.peek_next_token().unwrap() {
             Token::Keyword(Keyword::Void) => {
@@ -121,18 +247,28 @@ impl Engine {
             }
         }
         // subroutineName '(' parameterList ')'
-        let fname = self.class_name.clone() + "." + &self.compile_subroutine_name();
+        let sub_name = self.compile_subroutine_name();
+        let name_span = self.span();
+        if !is_lower_camel_case(&sub_name) {
+            let span = self.span();
+            self.lint(Lint::Naming, span, format!("subroutine name {} should be lowerCamelCase", sub_name));
+        }
+        let fname = self.class_name.clone() + "." + &sub_name;
+        self.subroutines.push(SubroutineRefs { kind: subroutine_type, name: fname.clone(), refs_start: self.sub_refs_start });
         self.compile_symbol_expect(Symbol::ParenL);
         if subroutine_type == Keyword::Method {
-            self.sym_tbl.define("this", VarKind::Arg, VarType::ClassName(self.class_name.clone()))
+            self.sym_tbl.define("this", VarKind::Arg, VarType::ClassName(self.class_name.clone())).unwrap();
         }
         self.compile_parameter_list();
         self.compile_symbol_expect(Symbol::ParenR);
         // subroutineBody
-        self.compile_subroutine_body(&fname, subroutine_type);
+        self.compile_subroutine_body(&fname, subroutine_type, name_span);
+        self.lint_unused(self.sub_refs_start, &[VarKind::Arg, VarKind::Var]);
+        self.lint_read_before_assign();
     }
 
-    fn compile_subroutine_body(&mut self, fun_name: &str, subroutine_type: Keyword) {
+    // `name_span` locates the header and the setup of `this` in the source map.
+    fn compile_subroutine_body(&mut self, fun_name: &str, subroutine_type: Keyword, name_span: Span) {
         // '{'
         self.compile_symbol_expect(Symbol::BraceL);
         // varDec*
@@ -144,7 +280,9 @@ impl Engine {
                 _ => { break 'varDec; }
             }
         }
-        self.vm_writer.write_function(fun_name, self.sym_tbl.var_count(VarKind::Var) as i16);
+        // the header is written once the body is compiled, when the locals declared in blocks are known
+        self.vm_writer.begin_capture();
+        self.vm_writer.set_location(name_span);
         match subroutine_type {
             Keyword::Constructor => {
                 let size = self.sym_tbl.var_count(VarKind::Field) as i16;
@@ -159,9 +297,17 @@ impl Engine {
             _ => (),
         }
         // statements
-        self.compile_statements();
+        let returns = self.compile_statements();
         // '}'
         self.compile_symbol_expect(Symbol::BraceR);
+        let body = self.vm_writer.end_capture();
+        self.vm_writer.set_location(name_span);
+        self.vm_writer.write_function(fun_name, self.sym_tbl.local_count() as i16);
+        self.vm_writer.write_captured(body);
+        if !returns {
+            let span = self.span();
+            self.lint(Lint::MissingReturn, span, format!("{} does not end with a return statement", fun_name));
+        }
     }
 
     fn compile_parameter_list(&mut self) {
@@ -186,6 +332,8 @@ impl Engine {
         }
     }
 
+    // A local starts at 0 wherever it is declared: when it is declared in a block, or its slot held a local of a block
+    // that has ended, the slot may hold an earlier value, or its own from a previous pass through a loop, so it is cleared.
     fn compile_var_dec(&mut self) {
         // 'var'
         self.compile_keyword_expect(Keyword::Var);
@@ -193,8 +341,14 @@ impl Engine {
         let vartype = self.compile_type().unwrap();
         // varName (',' varName)*
         'varName: loop {
+            let reused = self.sym_tbl.var_count(VarKind::Var) < self.sym_tbl.local_count();
             // varName
-            self.compile_var_name_defined(VarKind::Var, vartype.clone());
+            let name = self.compile_var_name_defined(VarKind::Var, vartype.clone());
+            if self.sym_tbl.in_block() || reused {
+                let index = *self.sym_tbl.index_of(&name).unwrap() as i16;
+                self.vm_writer.write_push(Segment::Const, 0);
+                self.vm_writer.write_pop_var(Segment::Local, index, &name);
+            }
             // ','
             match self.tokenizer.peek_next_token().unwrap() {
                 &Token::Symbol(Symbol::Comma) => {
@@ -207,26 +361,44 @@ impl Engine {
         self.compile_symbol_expect(Symbol::SemiColon);
     }
 
-    fn compile_statements(&mut self) {
+    // Returns whether the statements always end by returning.
+    fn compile_statements(&mut self) -> bool {
+        let mut returns = false;
         // statement*
         'statement: loop {
+            self.annotate_statement();
             match self.tokenizer.peek_next_token().unwrap() {
                 Token::Keyword(stat) => {
                     match stat {
                         Keyword::Let => {
                             self.compile_let();
+                            returns = false;
                         },
                         Keyword::If => {
-                            self.compile_if();
+                            returns = self.compile_if();
                         },
                         Keyword::While => {
                             self.compile_while();
+                            returns = false;
+                        },
+                        Keyword::For => {
+                            self.compile_for();
+                            returns = false;
+                        },
+                        Keyword::Break | Keyword::Continue => {
+                            self.compile_jump();
+                            returns = false;
                         },
                         Keyword::Do => {
                             self.compile_do();
+                            returns = false;
                         },
                         Keyword::Return => {
                             self.compile_return();
+                            returns = true;
+                        },
+                        Keyword::Var if self.options.extensions => {
+                            self.compile_var_dec();
                         },
                         s => {
                             panic!("'let', 'if', 'while', 'do', or 'return' expected, found {:?}", s);
@@ -236,20 +408,62 @@ impl Engine {
                 _ => { break 'statement; }
             }
         }
+        returns
+    }
+
+    // With --annotate, writes the source line of the statement that comes next as a comment, once per line.
+    fn annotate_statement(&mut self) {
+        if !self.options.annotate {
+            return;
+        }
+        let line = match self.tokenizer.source_tokens().next() {
+            Some((Token::Keyword(_), span)) => span.line,
+            _ => { return; }
+        };
+        if line != self.annotated_line {
+            self.annotated_line = line;
+            let text = format!("{}.jack:{}: {}", self.class_name, line, self.tokenizer.source_line(line).trim());
+            self.vm_writer.write_comment(&text);
+        }
+    }
+
+    // '{' statements '}', a scope of its own for the variables declared in it.
+    // Returns whether the statements always end by returning.
+    fn compile_block(&mut self) -> bool {
+        self.compile_symbol_expect(Symbol::BraceL);
+        self.sym_tbl.begin_scope();
+        let returns = self.compile_statements();
+        self.sym_tbl.end_scope();
+        self.compile_symbol_expect(Symbol::BraceR);
+        returns
     }
 
     fn compile_do(&mut self) {
         // 'do' subroutineCall ';'
         self.compile_keyword_expect(Keyword::Do);
-        self.compile_subroutine_call();
+        let span = self.span();
+        let (cls_name, fun_name) = self.compile_subroutine_call();
         self.compile_symbol_expect(Symbol::SemiColon);
+        if let Some(t) = self.subroutine_decl(&cls_name, &fun_name).and_then(|s| s.return_type) {
+            self.lint(Lint::DiscardedReturn, span, format!("value of type {} returned by {}.{} is discarded", t, cls_name, fun_name));
+        }
         self.vm_writer.write_pop(Segment::Temp, 0); // 値の廃棄にはtemp 0を使用
     }
 
     fn compile_let(&mut self) {
-        // 'let' varName 
+        // 'let' assignment ';'
         self.compile_keyword_expect(Keyword::Let);
+        self.compile_assignment();
+        self.compile_symbol_expect(Symbol::SemiColon);
+    }
+
+    // varName ('[' expression ']')? ('=' expression | compoundOp expression | '++' | '--')
+    fn compile_assignment(&mut self) {
+        // varName
         let var_name = self.compile_var_name_used();
+        if self.sym_tbl.kind_of(&var_name) == Some(&VarKind::Const) {
+            panic!("cannot assign to constant {}", var_name);
+        }
         let var_seg = self._seg_of(&var_name);
         let var_index = *self.sym_tbl.index_of(&var_name).unwrap() as i16;
         // ('[' expression ']')?
@@ -259,27 +473,72 @@ impl Engine {
             self.compile_expression();
             self.compile_symbol_expect(Symbol::SqParR);
             // 対象要素のアドレスを計算
-            self.vm_writer.write_push(var_seg, var_index);
+            self.vm_writer.write_push_var(var_seg, var_index, &var_name);
             self.vm_writer.write_arithmetic(Command::Add);
+            if let Some(op) = self.compile_compound_operator() {
+                // the address stays on the stack while the new value is computed, so it is evaluated only once
+                self.vm_writer.write_pop(Segment::Pointer, 1);
+                self.vm_writer.write_push(Segment::Pointer, 1);
+                self.vm_writer.write_push(Segment::That, 0);
+                self.compile_compound_operand(op);
+                self.vm_writer.write_pop(Segment::Temp, 0);
+                self.vm_writer.write_pop(Segment::Pointer, 1);
+                self.vm_writer.write_push(Segment::Temp, 0);
+                self.vm_writer.write_pop(Segment::That, 0);
+                return;
+            }
             self.vm_writer.write_pop(Segment::Temp, 1); // temp 1に左辺アドレスを退避
-            // '=' expression ';'
+            // '=' expression
             self.compile_symbol_expect(Symbol::Equal);
             self.compile_expression();
-            self.compile_symbol_expect(Symbol::SemiColon);
             // アドレス戻し
             self.vm_writer.write_push(Segment::Temp, 1);
             self.vm_writer.write_pop(Segment::Pointer, 1);
             // 要素に代入
             self.vm_writer.write_pop(Segment::That, 0);
+        } else if let Some(op) = self.compile_compound_operator() {
+            // the target is read before the operand is evaluated and written after it
+            let target = self.refs.len() - 1;
+            self.vm_writer.write_push_var(var_seg, var_index, &var_name);
+            self.compile_compound_operand(op);
+            self.vm_writer.write_pop_var(var_seg, var_index, &var_name);
+            let mut r = self.refs[target].clone();
+            r.write = true;
+            self.refs.push(r);
         } else {
-            // '=' expression ';'
+            let target = self.refs.len() - 1;
+            // '=' expression
             self.compile_symbol_expect(Symbol::Equal);
             self.compile_expression();
-            self.compile_symbol_expect(Symbol::SemiColon);
-            self.vm_writer.write_pop(var_seg, var_index);
+            self.vm_writer.write_pop_var(var_seg, var_index, &var_name);
+            // the assignment takes effect after the expression is evaluated
+            let mut r = self.refs.remove(target);
+            r.write = true;
+            self.refs.push(r);
         }
     }
 
+    // Consumes a compound assignment operator if one comes next, returning it.
+    fn compile_compound_operator(&mut self) -> Option<Symbol> {
+        match self.tokenizer.peek_next_token() {
+            Some(&Token::Symbol(sym)) if compound_base(sym).is_some() => Some(self.compile_symbol()),
+            _ => None,
+        }
+    }
+
+    // With the target's value on the stack, computes its new value for `op`.
+    fn compile_compound_operand(&mut self, op: Symbol) {
+        match op {
+            Symbol::Increment | Symbol::Decrement => {
+                self.vm_writer.write_push(Segment::Const, 1);
+            },
+            _ => {
+                self.compile_expression();
+            }
+        }
+        self.write_binary_op(compound_base(op).unwrap());
+    }
+
     fn compile_while(&mut self) {
         // 'while' '(' expression ')'
         let w_cnt = self.while_count;
@@ -295,16 +554,79 @@ impl Engine {
         self.vm_writer.write_if(&while_end_label);
 
         // '{' statements '}'
-        self.compile_symbol_expect(Symbol::BraceL);
-        self.compile_statements();
-        self.compile_symbol_expect(Symbol::BraceR);
+        self.loops.push((while_label.clone(), while_end_label.clone()));
+        self.compile_block();
+        self.loops.pop();
+        self.vm_writer.write_goto(&while_label);
+        self.vm_writer.write_label(&while_end_label);
+    }
+
+    // The step is emitted before the condition so that `continue` can jump to WHILE_EXP like in a while loop:
+    //   init; goto FOR_COND; WHILE_EXP: step; FOR_COND: if !cond goto WHILE_END; body; goto WHILE_EXP; WHILE_END:
+    fn compile_for(&mut self) {
+        // 'for' '(' assignment? ';' expression? ';' assignment? ')'
+        let w_cnt = self.while_count;
+        let while_label = format!("WHILE_EXP{}", w_cnt);
+        let while_end_label = format!("WHILE_END{}", w_cnt);
+        let for_cond_label = format!("FOR_COND{}", w_cnt);
+        self.while_count += 1;
+        self.compile_keyword_expect(Keyword::For);
+        self.compile_symbol_expect(Symbol::ParenL);
+        if self.tokenizer.peek_next_token() != Some(&Token::Symbol(Symbol::SemiColon)) {
+            self.compile_assignment(); // init
+        }
+        self.compile_symbol_expect(Symbol::SemiColon);
+        self.vm_writer.begin_capture();
+        if self.tokenizer.peek_next_token() != Some(&Token::Symbol(Symbol::SemiColon)) {
+            self.compile_expression(); // loop condition
+            self.vm_writer.write_arithmetic(Command::Not);
+            self.vm_writer.write_if(&while_end_label);
+        }
+        let condition = self.vm_writer.end_capture();
+        self.compile_symbol_expect(Symbol::SemiColon);
+        self.vm_writer.write_goto(&for_cond_label);
+        self.vm_writer.write_label(&while_label);
+        if self.tokenizer.peek_next_token() != Some(&Token::Symbol(Symbol::ParenR)) {
+            self.compile_assignment(); // step
+        }
+        self.compile_symbol_expect(Symbol::ParenR);
+        self.vm_writer.write_label(&for_cond_label);
+        self.vm_writer.write_captured(condition);
+
+        // '{' statements '}'
+        self.loops.push((while_label.clone(), while_end_label.clone()));
+        self.compile_block();
+        self.loops.pop();
         self.vm_writer.write_goto(&while_label);
         self.vm_writer.write_label(&while_end_label);
     }
 
+    fn compile_jump(&mut self) {
+        // ('break' | 'continue') ';'
+        let kw = self.compile_keyword();
+        let (continue_label, break_label) = match self.loops.last() {
+            Some(labels) => labels.clone(),
+            None => { panic!("'{}' outside of a loop", kw); }
+        };
+        self.compile_symbol_expect(Symbol::SemiColon);
+        if kw == Keyword::Break {
+            self.vm_writer.write_goto(&break_label);
+        } else {
+            self.vm_writer.write_goto(&continue_label);
+        }
+    }
+
     fn compile_return(&mut self) {
         // 'return'
         self.compile_keyword_expect(Keyword::Return);
+        if self.subroutine_kind == Keyword::Constructor {
+            let returns_this = self.tokenizer.peek_next_token() == Some(&Token::Keyword(Keyword::This))
+                && self.tokenizer.peek_2nd_next_token() == Some(&Token::Symbol(Symbol::SemiColon));
+            if !returns_this {
+                let span = self.span();
+                self.lint(Lint::ConstructorReturn, span, String::from("constructor should return this"));
+            }
+        }
         // expression?
         match self.tokenizer.peek_next_token().unwrap() {
             &Token::Symbol(Symbol::SemiColon) => {
@@ -319,7 +641,8 @@ impl Engine {
         self.vm_writer.write_return();
     }
 
-    fn compile_if(&mut self) {
+    // Returns whether both branches end by returning.
+    fn compile_if(&mut self) -> bool {
         let i_cnt = self.if_count;
         self.if_count += 1;
         // 'if' '(' expression ')'
@@ -333,88 +656,171 @@ impl Engine {
         self.vm_writer.write_goto(&if_false_label);
         // '{' statements '}'
         self.vm_writer.write_label(&if_true_label);
-        self.compile_symbol_expect(Symbol::BraceL);
-        self.compile_statements();
-        self.compile_symbol_expect(Symbol::BraceR);
-        // ('else' '{' statements '}')?
+        let then_returns = self.compile_block();
+        // ('else' ('{' statements '}' | ifStatement))?
         if let &Token::Keyword(Keyword::Else) = self.tokenizer.peek_next_token().unwrap() {
             // 'else' '{' statements '}'
             let if_end_label = format!("IF_END{}", i_cnt);
             self.vm_writer.write_goto(&if_end_label);
             self.vm_writer.write_label(&if_false_label);
             self.compile_keyword_expect(Keyword::Else);
-            self.compile_symbol_expect(Symbol::BraceL);
-            self.compile_statements();
-            self.compile_symbol_expect(Symbol::BraceR);
+            let else_returns = if self.options.extensions && self.tokenizer.peek_next_token() == Some(&Token::Keyword(Keyword::If)) {
+                // 'else' ifStatement
+                self.compile_if()
+            } else {
+                self.compile_block()
+            };
             self.vm_writer.write_label(&if_end_label);
+            then_returns && else_returns
         } else {
             self.vm_writer.write_label(&if_false_label);
+            false
         }
     }
 
     fn compile_expression(&mut self) {
+        if self.options.precedence {
+            self.compile_binary_expression(0);
+            return;
+        }
         // term
         self.compile_term();
         // (op term)*
+        let mut ops = vec![];
         'term: loop {
             match self.tokenizer.peek_next_token().unwrap() {
                 Token::Symbol(
                     Symbol::Plus | Symbol::Minus | Symbol::Asterisk | Symbol::Slash |
-                    Symbol::And  | Symbol::Or    | Symbol::LessThan | Symbol::GreaterThan | Symbol::Equal
+                    Symbol::And  | Symbol::Or    | Symbol::LessThan | Symbol::GreaterThan | Symbol::Equal |
+                    Symbol::AndAnd | Symbol::OrOr
                 ) => {
                     let sym = self.compile_symbol();
-                    self.compile_term();
-                    match sym {
-                        Symbol::Plus => {
-                            self.vm_writer.write_arithmetic(Command::Add);
-                        },
-                        Symbol::Minus => {
-                            self.vm_writer.write_arithmetic(Command::Sub);
-                        },
-                        Symbol::Asterisk => {
-                            self.vm_writer.write_call("Math.multiply", 2);
-                        },
-                        Symbol::Slash => {
-                            self.vm_writer.write_call("Math.divide", 2);
-                        },
-                        Symbol::And => {
-                            self.vm_writer.write_arithmetic(Command::And);
-                        },
-                        Symbol::Or => {
-                            self.vm_writer.write_arithmetic(Command::Or);
-                        },
-                        Symbol::LessThan => {
-                            self.vm_writer.write_arithmetic(Command::Lt);
-                        },
-                        Symbol::GreaterThan => {
-                            self.vm_writer.write_arithmetic(Command::Gt);
-                        },
-                        Symbol::Equal => {
-                            self.vm_writer.write_arithmetic(Command::Eq);
-                        }
-                        _ => { unreachable!(); }
-                    };
+                    ops.push((sym, self.span()));
+                    self.compile_operation(sym, |e| e.compile_term());
                 },
                 _ => {
                     break 'term;
                 }
             }
         }
+        // Jack applies the operators left to right: warn where C would group them differently.
+        if let Some(w) = ops.windows(2).find(|w| c_precedence(w[1].0) > c_precedence(w[0].0)) {
+            let (first, then) = (w[0].1, w[1].1);
+            let message = format!("'{}' is applied before '{}' here, unlike in C; add parentheses",
+                self.tokenizer.lexeme(&first), self.tokenizer.lexeme(&then));
+            self.lint(Lint::MixedPrecedence, then, message);
+        }
+    }
+
+    // Precedence climbing: term (op term)* where every op binds at least as tightly as `min_precedence`.
+    fn compile_binary_expression(&mut self, min_precedence: u8) {
+        self.compile_term();
+        while let Some(&Token::Symbol(sym)) = self.tokenizer.peek_next_token() {
+            let precedence = match sym.precedence() {
+                Some(p) if p >= min_precedence => p,
+                _ => { break; }
+            };
+            self.compile_symbol();
+            self.compile_operation(sym, |e| e.compile_binary_expression(precedence + 1));
+        }
+    }
+
+    // Compiles the right operand with `rhs` and applies `sym` to both operands. The left operand is
+    // already on the stack; && and || only evaluate the right one when the left does not decide the result,
+    // and turn it into true (-1) or false (0) so that ~ and = work on the result as on a comparison.
+    fn compile_operation(&mut self, sym: Symbol, rhs: impl FnOnce(&mut Self)) {
+        match sym {
+            Symbol::AndAnd => {
+                let l_cnt = self.logic_count;
+                self.logic_count += 1;
+                let rhs_label = format!("AND_RHS{}", l_cnt);
+                let end_label = format!("AND_END{}", l_cnt);
+                self.vm_writer.write_if(&rhs_label);
+                self.vm_writer.write_push(Segment::Const, 0);
+                self.vm_writer.write_goto(&end_label);
+                self.vm_writer.write_label(&rhs_label);
+                rhs(self);
+                self.write_truth();
+                self.vm_writer.write_label(&end_label);
+            },
+            Symbol::OrOr => {
+                let l_cnt = self.logic_count;
+                self.logic_count += 1;
+                let true_label = format!("OR_TRUE{}", l_cnt);
+                let end_label = format!("OR_END{}", l_cnt);
+                self.vm_writer.write_if(&true_label);
+                rhs(self);
+                self.write_truth();
+                self.vm_writer.write_goto(&end_label);
+                self.vm_writer.write_label(&true_label);
+                self.vm_writer.write_push(Segment::Const, 1);
+                self.vm_writer.write_arithmetic(Command::Neg);
+                self.vm_writer.write_label(&end_label);
+            },
+            _ => {
+                rhs(self);
+                self.write_binary_op(sym);
+            }
+        }
+    }
+
+    // Replaces the value on the stack with the boolean it stands for: 0 is false, anything else true (-1).
+    fn write_truth(&mut self) {
+        self.vm_writer.write_push(Segment::Const, 0);
+        self.vm_writer.write_arithmetic(Command::Eq);
+        self.vm_writer.write_arithmetic(Command::Not);
+    }
+
+    fn write_binary_op(&mut self, sym: Symbol) {
+        match sym {
+            Symbol::Plus => {
+                self.vm_writer.write_arithmetic(Command::Add);
+            },
+            Symbol::Minus => {
+                self.vm_writer.write_arithmetic(Command::Sub);
+            },
+            Symbol::Asterisk => {
+                self.vm_writer.write_call("Math.multiply", 2);
+            },
+            Symbol::Slash => {
+                self.vm_writer.write_call("Math.divide", 2);
+            },
+            Symbol::And => {
+                self.vm_writer.write_arithmetic(Command::And);
+            },
+            Symbol::Or => {
+                self.vm_writer.write_arithmetic(Command::Or);
+            },
+            Symbol::LessThan => {
+                self.vm_writer.write_arithmetic(Command::Lt);
+            },
+            Symbol::GreaterThan => {
+                self.vm_writer.write_arithmetic(Command::Gt);
+            },
+            Symbol::Equal => {
+                self.vm_writer.write_arithmetic(Command::Eq);
+            }
+            _ => { unreachable!(); }
+        };
     }
 
     fn compile_term(&mut self) {
         match self.tokenizer.peek_next_token().unwrap() {
             &Token::IntConst(_) => {
                 let i = self.compile_integer_constant();
-                self.vm_writer.write_push(Segment::Const, i);
+                if i > 32767 {
+                    panic!("integer constant {} is out of range (0..32767)", i);
+                }
+                self.vm_writer.write_push(Segment::Const, i as i16);
             },
             &Token::StringConst(_) => {
+                // one character code per char, not per UTF-8 byte
                 let s = self.compile_string_constant();
-                let length = s.len() as i16;
+                let length = s.chars().count() as i16;
                 self.vm_writer.write_push(Segment::Const, length);
                 self.vm_writer.write_call("String.new", 1);
-                for i in 0..s.len() {
-                    self.vm_writer.write_push(Segment::Const, s.chars().nth(i).unwrap() as i16);
+                for c in s.chars() {
+                    self.vm_writer.write_push(Segment::Const, c as i16);
                     self.vm_writer.write_call("String.appendChar", 2);
                 }
             },
@@ -429,6 +835,9 @@ impl Engine {
                         self.vm_writer.write_push(Segment::Const, 0);
                     },
                     Keyword::This => {
+                        if self.subroutine_kind == Keyword::Function {
+                            panic!("'this' cannot be used in a function");
+                        }
                         self.vm_writer.write_push(Segment::Pointer, 0);
                     },
                     _ => { unreachable!() }
@@ -436,7 +845,7 @@ impl Engine {
             },
             &Token::Identifier(_) => {
                 match self.tokenizer.peek_2nd_next_token().unwrap() {
-                    &Token::Symbol(Symbol::SqParL) => {
+                    Token::Symbol(Symbol::SqParL) => {
                         // varName '[' expression ']'
                         let var_name = self.compile_var_name_used();
                         let var_seg = self._seg_of(&var_name);
@@ -446,27 +855,46 @@ impl Engine {
                         self.compile_expression(); // array index
                         self.compile_symbol_expect(Symbol::SqParR);
                         // アドレス計算、参照先設定
-                        self.vm_writer.write_push(var_seg, var_index);
+                        self.vm_writer.write_push_var(var_seg, var_index, &var_name);
                         self.vm_writer.write_arithmetic(Command::Add);
                         self.vm_writer.write_pop(Segment::Pointer, 1);
                         self.vm_writer.write_push(Segment::That, 0);
                     },
-                    &Token::Symbol(Symbol::ParenL | Symbol::Dot) => {
+                    Token::Symbol(Symbol::Dot) if self.options.extensions && self.tokenizer.peek_nth_token(3) != Some(&Token::Symbol(Symbol::ParenL)) => {
+                        // className '.' constantName
+                        let cls_name = self.compile_class_name();
+                        self.compile_symbol_expect(Symbol::Dot);
+                        let name = format!("{}.{}", cls_name, self.compile_identifier());
+                        match self.constant_value(&name) {
+                            Some(value) => self.write_constant(value),
+                            None => { panic!("unknown constant {}", name); }
+                        }
+                    },
+                    Token::Symbol(Symbol::ParenL | Symbol::Dot) => {
                         // subroutineCall
                         self.compile_subroutine_call();
                     },
                     _ => {
-                        // varName
+                        // varName | constantName
                         let var_name = self.compile_var_name_used();
+                        if let Some(value) = self.sym_tbl.value_of(&var_name) {
+                            self.write_constant(value);
+                            return;
+                        }
                         let var_seg = self._seg_of(&var_name);
                         let var_index = *self.sym_tbl.index_of(&var_name).unwrap() as i16;
-                        self.vm_writer.write_push(var_seg, var_index);
+                        self.vm_writer.write_push_var(var_seg, var_index, &var_name);
                     }
                 }
             },
             &Token::Symbol(Symbol::Minus | Symbol::Not) => {
                 // unaryOp term
                 let sym = self.compile_symbol();
+                if sym == Symbol::Minus && self.tokenizer.peek_next_token() == Some(&Token::IntConst(INT_CONST_MAX)) {
+                    self.compile_integer_constant();
+                    self.write_constant(i16::MIN);
+                    return;
+                }
                 self.compile_term();
                 match sym {
                     Symbol::Minus => {
@@ -490,15 +918,31 @@ impl Engine {
         }
     }
 
-    fn compile_expression_list(&mut self) -> i16 {
-        let mut count = 0;
+    // Pushes a value known at compile time, such as a named constant.
+    fn write_constant(&mut self, value: i16) {
+        if value == i16::MIN {
+            // -32768 has no positive counterpart in 16 bits: build it as -32767 - 1
+            self.vm_writer.write_push(Segment::Const, 32767);
+            self.vm_writer.write_arithmetic(Command::Neg);
+            self.vm_writer.write_push(Segment::Const, 1);
+            self.vm_writer.write_arithmetic(Command::Sub);
+        } else if value < 0 {
+            self.vm_writer.write_push(Segment::Const, -value);
+            self.vm_writer.write_arithmetic(Command::Neg);
+        } else {
+            self.vm_writer.write_push(Segment::Const, value);
+        }
+    }
+
+    // Returns the type of each argument when it is evident, and where it ends.
+    fn compile_expression_list(&mut self) -> Vec<(Option<VarType>, Span)> {
+        let mut args = vec![];
         // (expression (',' expression)* )?
         match self.tokenizer.peek_next_token().unwrap() {
             &Token::Symbol(Symbol::ParenR) => (),
             _ => {
                 // expression (',' expression)*
-                self.compile_expression();
-                count += 1;
+                args.push(self.compile_argument());
                 'expression: loop {
                     match self.tokenizer.peek_next_token().unwrap() {
                         &Token::Symbol(Symbol::Comma) => {
@@ -506,15 +950,30 @@ impl Engine {
                         },
                         _ => { break 'expression; }
                     }
-                    self.compile_expression();
-                    count += 1;
+                    args.push(self.compile_argument());
                 }
             }
         }
-        count
+        args
+    }
+
+    // An argument, with its type when the expression is a single literal or variable.
+    fn compile_argument(&mut self) -> (Option<VarType>, Span) {
+        let single = matches!(self.tokenizer.peek_2nd_next_token(), Some(Token::Symbol(Symbol::Comma | Symbol::ParenR)));
+        let evident = match self.tokenizer.peek_next_token() {
+            _ if !single => None,
+            Some(Token::IntConst(_)) => Some(VarType::Int),
+            Some(Token::StringConst(_)) => Some(VarType::ClassName(String::from("String"))),
+            Some(Token::Keyword(Keyword::True | Keyword::False)) => Some(VarType::Boolean),
+            Some(Token::Identifier(name)) => self.sym_tbl.type_of(name).cloned(),
+            _ => None,
+        };
+        self.compile_expression();
+        (evident, self.span())
     }
 
-    fn compile_subroutine_call(&mut self) {
+    // Returns the class and subroutine names of the callee.
+    fn compile_subroutine_call(&mut self) -> (String, String) {
         let mut is_method = false;
         // function | method | constructor?
         let sym = match self.tokenizer.peek_2nd_next_token().unwrap() {
@@ -529,12 +988,12 @@ impl Engine {
                 Token::Identifier(i) => i,
                 t => { panic!("identifier expected, found {:?}", t); }
             };
-            let cls_name = if self.sym_tbl.contains(&i) { // method
+            let cls_name = if self.sym_tbl.contains(i) { // method
                 is_method = true;
                 let var_name = self.compile_var_name_used();
                 let var_seg = self._seg_of(&var_name);
                 let var_index = *self.sym_tbl.index_of(&var_name).unwrap() as i16;
-                self.vm_writer.write_push(var_seg, var_index);
+                self.vm_writer.write_push_var(var_seg, var_index, &var_name);
                 let cn = match self.sym_tbl.type_of(&var_name).unwrap() {
                     VarType::ClassName(cn) => cn,
                     vt => { panic!("class name expected, found {:?}", vt); } 
@@ -546,20 +1005,68 @@ impl Engine {
             self.compile_symbol_expect(Symbol::Dot);
             cls_name
         } else { // method call within its belonging class
+            if self.subroutine_kind == Keyword::Function {
+                let name = self.compile_subroutine_name();
+                panic!("method {} cannot be called without an object in a function; \
+                        call {}.{}() if it is a function", name, self.class_name, name);
+            }
             is_method = true;
             self.vm_writer.write_push(Segment::Pointer, 0);
             self.class_name.clone()
         };
         let fun_name = self.compile_subroutine_name();
+        let name_span = self.span();
         let fname = format!("{}.{}", cls_name, fun_name);
         // '(' expressionList ')'
         self.compile_symbol_expect(Symbol::ParenL);
-        let mut num_exp = self.compile_expression_list();
+        let args = self.compile_expression_list();
         self.compile_symbol_expect(Symbol::ParenR);
+        self.check_call(&cls_name, &fun_name, is_method, &args, name_span);
+        let mut num_exp = args.len() as i16;
         if is_method {
             num_exp += 1;
         }
         self.vm_writer.write_call(&fname, num_exp);
+        (cls_name, fun_name)
+    }
+
+    // The declaration of a subroutine of this class or of a class in the registry.
+    fn subroutine_decl(&self, cls_name: &str, fun_name: &str) -> Option<SubroutineDecl> {
+        if cls_name == self.class_name {
+            self.class_decl.as_ref().and_then(|c| c.subroutine(fun_name)).cloned()
+        } else {
+            self.registry.subroutine(cls_name, fun_name).cloned()
+        }
+    }
+
+    // Checks a call against the declaration of the callee when its class is known.
+    fn check_call(&mut self, cls_name: &str, fun_name: &str, is_method: bool, args: &[(Option<VarType>, Span)], span: Span) {
+        let known = cls_name == self.class_name || self.registry.class(cls_name).is_some();
+        let message = match self.subroutine_decl(cls_name, fun_name) {
+            None if known => format!("{} has no subroutine named {}", cls_name, fun_name),
+            None => { return; },
+            Some(s) if is_method && s.kind != Keyword::Method => {
+                format!("{}.{} is a {}, not a method; call it as {}.{}()", cls_name, fun_name, s.kind, cls_name, fun_name)
+            },
+            Some(s) if !is_method && s.kind == Keyword::Method => {
+                format!("{}.{} is a method; call it on an object", cls_name, fun_name)
+            },
+            Some(s) if s.params.len() != args.len() => {
+                format!("{}.{} expects {} argument(s), found {}", cls_name, fun_name, s.params.len(), args.len())
+            },
+            Some(s) => {
+                for (i, (param, (arg, arg_span))) in s.params.iter().zip(args.iter()).enumerate() {
+                    match arg {
+                        Some(t) if !is_assignable(t, &param.var_type) => {
+                            self.error(*arg_span, format!("argument {} of {}.{} should be {}, found {}", i + 1, cls_name, fun_name, param.var_type, t));
+                        },
+                        _ => (),
+                    }
+                }
+                return;
+            },
+        };
+        self.error(span, message);
     }
 
     fn compile_keyword_expect(&mut self, kw_expect: Keyword) {
@@ -579,7 +1086,7 @@ impl Engine {
     }
 
     fn compile_keyword(&mut self) -> Keyword {
-        match self.tokenizer.get_next_token() {
+        match self.next_token() {
             Token::Keyword(kw) => {
                 kw
             },
@@ -606,7 +1113,7 @@ impl Engine {
     }
 
     fn compile_symbol(&mut self) -> Symbol {
-        match self.tokenizer.get_next_token() {
+        match self.next_token() {
             Token::Symbol(sym) => {
                 sym
             },
@@ -617,7 +1124,7 @@ impl Engine {
     }
 
     fn compile_identifier(&mut self) -> String {
-        match self.tokenizer.get_next_token() {
+        match self.next_token() {
             Token::Identifier(ident) => {
                 ident
             },
@@ -627,8 +1134,8 @@ impl Engine {
         }
     }
 
-    fn compile_integer_constant(&mut self) -> i16 {
-        match self.tokenizer.get_next_token() {
+    fn compile_integer_constant(&mut self) -> u16 {
+        match self.next_token() {
             Token::IntConst(int_const) => {
                 int_const
             },
@@ -639,7 +1146,7 @@ impl Engine {
     }
 
     fn compile_string_constant(&mut self) -> String {
-        match self.tokenizer.get_next_token() {
+        match self.next_token() {
             Token::StringConst(str_const) => {
                 str_const
             },
@@ -658,9 +1165,35 @@ impl Engine {
     }
 
     fn compile_var_name_defined(&mut self, var_kind: VarKind, var_type: VarType) -> String {
-        match self.tokenizer.get_next_token() {
+        match self.next_token() {
             Token::Identifier(ident) => {
-                self.sym_tbl.define(&ident, var_kind, var_type);
+                let span = self.tokenizer.current_span().unwrap_or_default();
+                if let (VarKind::Arg | VarKind::Var, Some(VarKind::Static | VarKind::Field | VarKind::Const)) = (var_kind, self.sym_tbl.kind_of(&ident)) {
+                    let field = *self.sym_tbl.span_of(&ident).unwrap();
+                    let note = (field, format!("{} is declared here", ident));
+                    let message = format!("{} {} shadows a class variable", var_kind, ident);
+                    // a warning of every compilation, which `jackc lint` reports under its rule so that it can be allowed
+                    if self.lints.is_some() {
+                        self.lint_with_note(Lint::ShadowedField, span, message, Some(note));
+                    } else {
+                        self.diagnostics.push(Diagnostic::warning(Some(span), message).with_note(note.0, note.1));
+                    }
+                }
+                if var_kind == VarKind::Const {
+                    if !is_upper_snake_case(&ident) {
+                        self.lint(Lint::Naming, span, format!("constant name {} should be UPPER_SNAKE_CASE", ident));
+                    }
+                } else if !is_lower_camel_case(&ident) {
+                    self.lint(Lint::Naming, span, format!("variable name {} should be lowerCamelCase", ident));
+                }
+                match self.sym_tbl.define_at(&ident, var_kind, var_type, span) {
+                    Ok(()) => self.record_ref(&ident, span),
+                    Err(dup) => {
+                        let d = Diagnostic::error(Some(span), dup.to_string())
+                            .with_note(dup.previous, format!("previous declaration of {}", ident));
+                        self.diagnostics.push(d);
+                    }
+                }
                 ident
             },
             t => {
@@ -670,9 +1203,11 @@ impl Engine {
     }
 
     fn compile_var_name_used(&mut self) -> String {
-        match self.tokenizer.get_next_token() {
+        match self.next_token() {
             Token::Identifier(ident) => {
                 if self.sym_tbl.contains(&ident) {
+                    let span = self.tokenizer.current_span().unwrap_or_default();
+                    self.record_ref(&ident, span);
                     ident
                 } else {
                     panic!("variable {} is not registered", ident);
@@ -708,14 +1243,92 @@ impl Engine {
         }
     }
 
+    fn record_ref(&mut self, name: &str, span: Span) {
+        self.refs.push(SymbolRef {
+            name: name.to_string(),
+            span,
+            decl: *self.sym_tbl.span_of(name).unwrap(),
+            kind: *self.sym_tbl.kind_of(name).unwrap(),
+            var_type: self.sym_tbl.type_of(name).unwrap().clone(),
+            index: *self.sym_tbl.index_of(name).unwrap(),
+            write: false,
+        });
+    }
+
+    fn span(&self) -> Span {
+        self.tokenizer.current_span().unwrap_or_default()
+    }
+
+    // Consumes the next token; the code written until the next one is attributed to it in the source map.
+    fn next_token(&mut self) -> Token {
+        let token = self.tokenizer.get_next_token();
+        self.vm_writer.set_location(self.span());
+        token
+    }
+
+    // Reports an error that does not stop the compilation.
+    fn error(&mut self, span: Span, message: String) {
+        self.diagnostics.push(Diagnostic::error(Some(span), message));
+    }
+
+    fn lint(&mut self, lint: Lint, span: Span, message: String) {
+        self.lint_with_note(lint, span, message, None);
+    }
+
+    fn lint_with_note(&mut self, lint: Lint, span: Span, message: String, note: Option<(Span, String)>) {
+        if let Some((config, suppressions)) = &self.lints {
+            if config.is_enabled(lint) && !suppressions.is_allowed(lint, span) {
+                let mut d = Diagnostic::warning(Some(span), format!("{} [{}]", message, lint));
+                d.notes.extend(note);
+                self.diagnostics.push(d);
+            }
+        }
+    }
+
+    // Reports the variables of the given kinds declared in refs[start..] that are never used afterwards.
+    fn lint_unused(&mut self, start: usize, kinds: &[VarKind]) {
+        let unused: Vec<SymbolRef> = self.refs[start..].iter()
+            .filter(|d| d.span == d.decl && kinds.contains(&d.kind))
+            .filter(|d| !self.refs[start..].iter().any(|r| r.decl == d.decl && r.span != d.decl))
+            .cloned()
+            .collect();
+        for d in unused {
+            let lint = match d.kind {
+                VarKind::Var => Lint::UnusedLocal,
+                VarKind::Arg => Lint::UnusedArgument,
+                _ => Lint::UnusedField,
+            };
+            self.lint(lint, d.decl, format!("{} {} is never used", d.kind, d.name));
+        }
+    }
+
+    // Reports locals of the current subroutine whose first use reads them.
+    fn lint_read_before_assign(&mut self) {
+        let start = self.sub_refs_start;
+        let mut early_reads = vec![];
+        for d in self.refs[start..].iter().filter(|d| d.span == d.decl && d.kind == VarKind::Var) {
+            if let Some(first) = self.refs[start..].iter().find(|r| r.decl == d.decl && r.span != d.decl) {
+                if !first.write {
+                    early_reads.push(first.clone());
+                }
+            }
+        }
+        for r in early_reads {
+            self.lint(Lint::ReadBeforeAssign, r.span, format!("local {} is read before it is assigned", r.name));
+        }
+    }
+
     fn _seg_of(&self, var_name: &str) -> Segment {
-        match self.sym_tbl.kind_of(&var_name) {
+        match self.sym_tbl.kind_of(var_name) {
             Some(k) => {
                 match k {
                     VarKind::Static => {
                         Segment::Static
                     },
                     VarKind::Field => {
+                        if self.subroutine_kind == Keyword::Function {
+                            panic!("field {} cannot be used in a function", var_name);
+                        }
                         Segment::This
                     },
                     VarKind::Arg => {
@@ -724,6 +1337,9 @@ impl Engine {
                     VarKind::Var => {
                         Segment::Local
                     },
+                    VarKind::Const => {
+                        panic!("constant {} cannot be used as a variable", var_name);
+                    },
                 }
             },
             None => {
@@ -733,6 +1349,389 @@ impl Engine {
     }
 }
 
+// The binary operator applied by a compound assignment such as '+=' or '++'.
+fn compound_base(sym: Symbol) -> Option<Symbol> {
+    match sym {
+        Symbol::PlusAssign | Symbol::Increment  => Some(Symbol::Plus),
+        Symbol::MinusAssign | Symbol::Decrement => Some(Symbol::Minus),
+        Symbol::AsteriskAssign                  => Some(Symbol::Asterisk),
+        Symbol::SlashAssign                     => Some(Symbol::Slash),
+        Symbol::AndAssign                       => Some(Symbol::And),
+        Symbol::OrAssign                        => Some(Symbol::Or),
+        _ => None,
+    }
+}
+
+// Whether a value of type `arg` may be passed for a parameter of type `param`.
+// Jack converts freely between int, char and boolean, and an Array may hold the address of any object.
+fn is_assignable(arg: &VarType, param: &VarType) -> bool {
+    let is_array = |t: &VarType| *t == VarType::ClassName(String::from("Array"));
+    match (arg, param) {
+        (VarType::ClassName(_), VarType::ClassName(_)) => arg == param || is_array(arg) || is_array(param),
+        (VarType::ClassName(_), _) | (_, VarType::ClassName(_)) => is_array(arg) || is_array(param),
+        _ => true,
+    }
+}
+
+// Binding strength of the corresponding C operators, where '=' stands for '==' and '&' binds tighter than '|'.
+fn c_precedence(sym: Symbol) -> u8 {
+    match sym {
+        Symbol::Asterisk | Symbol::Slash       => 7,
+        Symbol::Plus | Symbol::Minus           => 6,
+        Symbol::LessThan | Symbol::GreaterThan => 5,
+        Symbol::Equal                          => 4,
+        Symbol::And                            => 3,
+        Symbol::Or                             => 2,
+        Symbol::AndAnd                         => 1,
+        _                                      => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+    use crate::compiler::catch_panic;
+    use crate::vm::*;
+
+    // Output sink the test keeps a handle on after the engine takes ownership of the writer.
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Compiles `body` as the body of Main.main and returns the VM code, or the error with its line and column.
+    fn compile_main(body: &str) -> Result<String, String> {
+        compile_main_with(&Options::default(), body)
+    }
+
+    fn compile_main_with(options: &Options, body: &str) -> Result<String, String> {
+        let src = format!("class Main {{\n    function void main() {{\n        var int x;\n        {}\n        return;\n    }}\n}}\n", body);
+        compile_class_with(options, &src, Registry::new())
+    }
+
+    fn compile_class_with(options: &Options, src: &str, registry: Registry) -> Result<String, String> {
+        let t = Tokenizer::with_options(src.as_bytes(), options);
+        if let Some(e) = t.errors.first() {
+            let span = e.span.unwrap();
+            return Err(format!("{}:{}: {}", span.line, span.col, e.message));
+        }
+        let out = Output::default();
+        let mut e = Engine::with_options(t, out.clone(), options.clone());
+        e.set_registry(registry);
+        catch_panic(|| e.compile()).map_err(|msg| {
+            let span = e.current_span().unwrap();
+            format!("{}:{}: {}", span.line, span.col, msg)
+        })?;
+        if let Some(d) = e.diagnostics().iter().find(|d| d.severity == Severity::Error) {
+            let span = d.span.unwrap();
+            return Err(format!("{}:{}: {}", span.line, span.col, d.message));
+        }
+        let vm = out.0.borrow();
+        Ok(String::from_utf8(vm.clone()).unwrap())
+    }
+
+    #[test]
+    fn test_int_const_boundaries() {
+        assert_eq!(compile_main("let x = 32767;").unwrap(),
+            "function Main.main 1\npush constant 32767\npop local 0\npush constant 0\nreturn\n");
+        assert_eq!(compile_main("let x = -32768;").unwrap(),
+            "function Main.main 1\npush constant 32767\nneg\npush constant 1\nsub\npop local 0\npush constant 0\nreturn\n");
+        assert_eq!(compile_main("let x = 32768;"), Err(String::from("4:17: integer constant 32768 is out of range (0..32767)")));
+        assert_eq!(compile_main("let x = 1 - 32768;"), Err(String::from("4:21: integer constant 32768 is out of range (0..32767)")));
+        assert_eq!(compile_main("let x = -(32768);"), Err(String::from("4:19: integer constant 32768 is out of range (0..32767)")));
+        assert_eq!(compile_main("let x = -40000;"), Err(String::from("4:18: integer constant 40000 is out of range (0..32767)")));
+
+        let ext = Options { extensions: true, ..Options::default() };
+        assert_eq!(compile_main_with(&ext, "let x = 'A' + 0x4000 - 0b11;").unwrap(),
+            "function Main.main 1\npush constant 65\npush constant 16384\nadd\npush constant 3\nsub\npop local 0\npush constant 0\nreturn\n");
+        assert_eq!(compile_main_with(&ext, "let x = -0x8000;").unwrap(),
+            "function Main.main 1\npush constant 32767\nneg\npush constant 1\nsub\npop local 0\npush constant 0\nreturn\n");
+        assert_eq!(compile_main_with(&ext, "let x = 0x8000;"), Err(String::from("4:17: integer constant 32768 is out of range (0..32767)")));
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        let expr = |options: &Options, e: &str| {
+            let vm = compile_main_with(options, &format!("let x = {};", e)).unwrap();
+            let lines: Vec<&str> = vm.lines().collect();
+            lines[1..lines.len() - 3].join("; ")
+        };
+        let plain = Options::default();
+        let prec = Options { precedence: true, ..Options::default() };
+        assert_eq!(expr(&plain, "1 + 2 * 3"),
+            "push constant 1; push constant 2; add; push constant 3; call Math.multiply 2");
+        assert_eq!(expr(&prec, "1 + 2 * 3"),
+            "push constant 1; push constant 2; push constant 3; call Math.multiply 2; add");
+        assert_eq!(expr(&prec, "8 - 4 - 2 / 2"),
+            "push constant 8; push constant 4; sub; push constant 2; push constant 2; call Math.divide 2; sub");
+        assert_eq!(expr(&prec, "x < 1 + 2 & ~(x = 3)"),
+            "push local 0; push constant 1; push constant 2; add; lt; push local 0; push constant 3; eq; not; and");
+    }
+
+    #[test]
+    fn test_short_circuit_operators() {
+        let expr = |options: &Options, e: &str| {
+            let vm = compile_main_with(options, &format!("let x = {};", e))?;
+            let lines: Vec<&str> = vm.lines().collect();
+            Ok::<String, String>(lines[1..lines.len() - 3].join("; "))
+        };
+        let ext = Options { extensions: true, ..Options::default() };
+        assert_eq!(expr(&ext, "(x > 0) && (x < 9)").unwrap(),
+            "push local 0; push constant 0; gt; if-goto AND_RHS0; push constant 0; goto AND_END0; \
+             label AND_RHS0; push local 0; push constant 9; lt; push constant 0; eq; not; label AND_END0");
+        assert_eq!(expr(&ext, "x || 1 && 2").unwrap(),
+            "push local 0; if-goto OR_TRUE0; push constant 1; push constant 0; eq; not; goto OR_END0; \
+             label OR_TRUE0; push constant 1; neg; label OR_END0; \
+             if-goto AND_RHS1; push constant 0; goto AND_END1; label AND_RHS1; push constant 2; push constant 0; eq; not; label AND_END1");
+        let ext_prec = Options { extensions: true, precedence: true, ..Options::default() };
+        assert_eq!(expr(&ext_prec, "x || 1 && 2").unwrap(),
+            "push local 0; if-goto OR_TRUE0; push constant 1; if-goto AND_RHS1; push constant 0; goto AND_END1; \
+             label AND_RHS1; push constant 2; push constant 0; eq; not; label AND_END1; push constant 0; eq; not; goto OR_END0; \
+             label OR_TRUE0; push constant 1; neg; label OR_END0");
+        assert_eq!(expr(&Options::default(), "x && x"), Err(String::from("4:19: unexpected token while parsing term: Symbol(And)")));
+        // the result is a boolean whatever the value of the right operand
+        let src = "class Main {\n    static int a, b;\n    function void main() {\n        var int x, y;\n\
+                   let x = 1; let y = 2; let a = ~(x && y); let b = (0 || y) = true;\n        return;\n    }\n}\n";
+        let vm = compile_class_with(&ext, src, Registry::new()).unwrap();
+        let mut vm = Vm::new(Program::parse(&[(String::from("Main.vm"), vm)]).unwrap()).unwrap();
+        let a = vm.address(Segment::Static, 0).unwrap();
+        while !vm.halted {
+            vm.step().unwrap();
+        }
+        assert_eq!((vm.ram[a], vm.ram[a + 1]), (0, -1));
+    }
+
+    #[test]
+    fn test_structured_control_flow() {
+        let ext = Options { extensions: true, ..Options::default() };
+        let body = |vm: String| {
+            let lines: Vec<&str> = vm.lines().collect();
+            lines[1..lines.len() - 2].join("; ")
+        };
+        assert_eq!(body(compile_main_with(&ext, "if (x) { let x = 1; } else if (x < 0) { let x = 2; } else { let x = 3; }").unwrap()),
+            "push local 0; if-goto IF_TRUE0; goto IF_FALSE0; label IF_TRUE0; push constant 1; pop local 0; goto IF_END0; \
+             label IF_FALSE0; push local 0; push constant 0; lt; if-goto IF_TRUE1; goto IF_FALSE1; label IF_TRUE1; push constant 2; pop local 0; \
+             goto IF_END1; label IF_FALSE1; push constant 3; pop local 0; label IF_END1; label IF_END0");
+        assert_eq!(body(compile_main_with(&ext, "for (x = 0; x < 3; x = x + 1) { if (x = 1) { continue; } break; }").unwrap()),
+            "push constant 0; pop local 0; goto FOR_COND0; label WHILE_EXP0; push local 0; push constant 1; add; pop local 0; \
+             label FOR_COND0; push local 0; push constant 3; lt; not; if-goto WHILE_END0; \
+             push local 0; push constant 1; eq; if-goto IF_TRUE0; goto IF_FALSE0; label IF_TRUE0; goto WHILE_EXP0; label IF_FALSE0; \
+             goto WHILE_END0; goto WHILE_EXP0; label WHILE_END0");
+        assert_eq!(body(compile_main_with(&ext, "while (x) { for (;;) { break; } continue; }").unwrap()),
+            "label WHILE_EXP0; push local 0; not; if-goto WHILE_END0; goto FOR_COND1; label WHILE_EXP1; label FOR_COND1; \
+             goto WHILE_END1; goto WHILE_EXP1; label WHILE_END1; goto WHILE_EXP0; goto WHILE_EXP0; label WHILE_END0");
+        assert_eq!(compile_main_with(&ext, "if (x) { break; }"), Err(String::from("4:18: 'break' outside of a loop")));
+        // without extensions these words are identifiers
+        assert_eq!(compile_main("let x = break;"), Err(String::from("4:17: variable break is not registered")));
+    }
+
+    #[test]
+    fn test_named_constants() {
+        let ext = Options { extensions: true, ..Options::default() };
+        let compile = |decls: &str, body: &str| {
+            let src = format!("class Main {{\n    {}\n    function void main() {{\n        var int x;\n        {}\n        return;\n    }}\n}}\n", decls, body);
+            let mut registry = Registry::new();
+            registry.load_stubs("class Screen { const int WIDTH = 512; }", &ext).unwrap();
+            let vm = compile_class_with(&ext, &src, registry)?;
+            let lines: Vec<&str> = vm.lines().collect();
+            Ok::<String, String>(lines[1..lines.len() - 2].join("; "))
+        };
+        assert_eq!(compile("const int SIZE = 4 * 8; const int NEG = -SIZE - 1; const boolean ON = true;",
+                           "let x = SIZE + NEG; let x = Main.SIZE; let x = ON;").unwrap(),
+            "push constant 32; push constant 33; neg; add; pop local 0; push constant 32; pop local 0; push constant 1; neg; pop local 0");
+        assert_eq!(compile("const int MIN = -32768; const int ROWS = Screen.WIDTH / 2;", "let x = MIN; let x = Screen.WIDTH + ROWS;").unwrap(),
+            "push constant 32767; neg; push constant 1; sub; pop local 0; push constant 512; push constant 256; add; pop local 0");
+        // a local of the same name hides the constant
+        assert_eq!(compile("const int N = 1;", "var int N; let N = 2; let x = N;"), Ok(String::from("push constant 2; pop local 1; push local 1; pop local 0")));
+        assert_eq!(compile("const int N = 1;", "let N = 2;"), Err(String::from("5:13: cannot assign to constant N")));
+        assert_eq!(compile("const int N = 1;", "let x = Sys.N;"), Err(String::from("5:21: unknown constant Sys.N")));
+        assert_eq!(compile("const int N = M + 1;", ""), Err(String::from("2:23: M is not a constant defined before this one")));
+        assert_eq!(compile("const int N = 1 / 0;", ""), Err(String::from("2:23: division by zero in constant expression")));
+        assert_eq!(compile("const Array A = 1;", ""), Err(String::from("2:11: constant type must be int, char or boolean, found Array")));
+        // without extensions Class.NAME is a subroutine call
+        assert_eq!(compile_main("let x = Screen.WIDTH;"), Err(String::from("4:24: ( expected, found SemiColon")));
+    }
+
+    #[test]
+    fn test_block_scoped_locals() {
+        let ext = Options { extensions: true, ..Options::default() };
+        let vm = compile_main_with(&ext, "while (x) { var int a; let a = x; } if (x) { var int b, c; let c = 1; let x = c; } let x = 2;").unwrap();
+        assert_eq!(vm.lines().next(), Some("function Main.main 3"));
+        assert!(vm.contains("push local 0\npop local 1\n"));
+        assert!(vm.contains("push constant 1\npop local 2\npush local 2\npop local 0\n"));
+        // an inner declaration hides the outer one until the end of its block
+        let vm = compile_main_with(&ext, "if (x) { var boolean x; let x = true; } let x = 1;").unwrap();
+        assert_eq!(vm, "function Main.main 2\npush local 0\nif-goto IF_TRUE0\ngoto IF_FALSE0\nlabel IF_TRUE0\npush constant 0\npop local 1\npush constant 1\nneg\npop local 1\n\
+                        label IF_FALSE0\npush constant 1\npop local 0\npush constant 0\nreturn\n");
+        assert_eq!(compile_main_with(&ext, "if (x) { var int a; } let a = 1;"), Err(String::from("4:35: variable a is not registered")));
+        assert_eq!(compile_main("if (x) { var int a; }"), Err(String::from("4:16: 'let', 'if', 'while', 'do', or 'return' expected, found Var")));
+    }
+
+    #[test]
+    fn test_block_locals_start_at_zero() {
+        let ext = Options { extensions: true, ..Options::default() };
+        let src = "class Main {\n    static int sum;\n    function void main() {\n        var int i;\n\
+                   while (i < 3) { var int a; let a = a + 1; let sum = sum + a; let i = i + 1; }\n\
+                   if (i = 3) { var int b; let b = b + 5; let sum = sum + b; }\n        return;\n    }\n}\n";
+        let vm = compile_class_with(&ext, src, Registry::new()).unwrap();
+        let mut vm = Vm::new(Program::parse(&[(String::from("Main.vm"), vm)]).unwrap()).unwrap();
+        let sum = vm.address(Segment::Static, 0).unwrap();
+        while !vm.halted {
+            vm.step().unwrap();
+        }
+        // a is 1 on each pass through the loop, b does not see the last value of a in the slot they share
+        assert_eq!(vm.ram[sum], 8);
+
+        // a local declared after a block takes its slot, cleared
+        let src = "class Main {\n    function int main() {\n        var int x;\n\
+                   if (true) { var int b; let b = 5; }\n        var int c;\n        return c;\n    }\n}\n";
+        let vm = compile_class_with(&ext, src, Registry::new()).unwrap();
+        assert!(vm.starts_with("function Main.main 2\n"));
+        let mut vm = Vm::new(Program::parse(&[(String::from("Main.vm"), vm)]).unwrap()).unwrap();
+        let result = vm.ram[ARG] as usize; // where the return value goes
+        while !vm.halted {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.ram[result], 0);
+    }
+
+    #[test]
+    fn test_function_context_checks() {
+        let compile = |body: &str| {
+            let src = format!("class Main {{\n    field int f;\n    function void main() {{\n        {}\n        return;\n    }}\n    method void run() {{ return; }}\n}}\n", body);
+            compile_class_with(&Options::default(), &src, Registry::new())
+        };
+        assert_eq!(compile("do Output.printInt(this);"), Err(String::from("4:28: 'this' cannot be used in a function")));
+        assert_eq!(compile("let f = 1;"), Err(String::from("4:13: field f cannot be used in a function")));
+        assert_eq!(compile("do Main.main(f);"), Err(String::from("4:22: field f cannot be used in a function")));
+        assert_eq!(compile("do run();"),
+            Err(String::from("4:12: method run cannot be called without an object in a function; call Main.run() if it is a function")));
+        assert!(compile("do Main.main();").is_ok());
+    }
+
+    #[test]
+    fn test_registry_call_checks() {
+        let compile = |body: &str| {
+            let src = format!("class Main {{\n    function void main() {{\n        var int x;\n        var Game g; {}\n        return;\n    }}\n}}\n", body);
+            let mut registry = Registry::new();
+            registry.load_stubs("class Game { constructor Game new(); method void run(int speed); function int score(); }", &Options::default()).unwrap();
+            compile_class_with(&Options::default(), &src, registry)
+        };
+        assert!(compile("let g = Game.new(); do g.run(3); let x = Game.score(); do Other.f(g);").is_ok());
+        assert_eq!(compile("do g.run();"), Err(String::from("4:26: Game.run expects 1 argument(s), found 0")));
+        assert_eq!(compile("do Game.run(1);"), Err(String::from("4:29: Game.run is a method; call it on an object")));
+        assert_eq!(compile("let x = g.score();"),
+            Err(String::from("4:31: Game.score is a function, not a method; call it as Game.score()")));
+        assert_eq!(compile("do Game.stop();"), Err(String::from("4:29: Game has no subroutine named stop")));
+        assert_eq!(compile("do Main.start();"), Err(String::from("4:29: Main has no subroutine named start")));
+    }
+
+    #[test]
+    fn test_os_call_checks() {
+        let compile = |body: &str| {
+            let src = format!("class Main {{\n    function void main() {{\n        var int x;\n        var String s; var Array a; {}\n        return;\n    }}\n}}\n", body);
+            compile_class_with(&Options::default(), &src, Registry::os())
+        };
+        assert!(compile("do Output.printString(\"hi\"); do Output.printChar(x); do Memory.deAlloc(s); let a = Memory.alloc(x + 1);").is_ok());
+        assert_eq!(compile("do Output.printInt(\"7\");"), Err(String::from("4:55: argument 1 of Output.printInt should be int, found String")));
+        assert_eq!(compile("do Screen.drawLine(0, 0, x, s);"), Err(String::from("4:64: argument 4 of Screen.drawLine should be int, found String")));
+        assert_eq!(compile("do Output.printString(s, 1);"), Err(String::from("4:46: Output.printString expects 1 argument(s), found 2")));
+        assert_eq!(compile("let x = s.length(1);"), Err(String::from("4:46: String.length expects 0 argument(s), found 1")));
+    }
+
+    #[test]
+    fn test_annotated_output() {
+        let annotate = Options { annotate: true, ..Options::default() };
+        let src = "class Main {\n    function void main() {\n        var int sum;\n        let sum = sum + 1;\n        if (sum) { let sum = 0; }\n        return;\n    }\n}\n";
+        assert_eq!(compile_class_with(&annotate, src, Registry::new()).unwrap(), "function Main.main 1
+// Main.jack:4: let sum = sum + 1;
+push local 0 // sum
+push constant 1
+add
+pop local 0 // sum
+// Main.jack:5: if (sum) { let sum = 0; }
+push local 0 // sum
+if-goto IF_TRUE0
+goto IF_FALSE0
+label IF_TRUE0
+push constant 0
+pop local 0 // sum
+label IF_FALSE0
+// Main.jack:6: return;
+push constant 0
+return
+");
+        // strict output by default
+        assert!(!compile_class_with(&Options::default(), src, Registry::new()).unwrap().contains("//"));
+    }
+
+    #[test]
+    fn test_compound_assignments() {
+        let ext = Options { extensions: true, ..Options::default() };
+        let body = |src: &str| {
+            let vm = compile_main_with(&ext, src).unwrap();
+            let lines: Vec<&str> = vm.lines().collect();
+            lines[1..lines.len() - 2].join("; ")
+        };
+        assert_eq!(body("let x += 2 * x;"), "push local 0; push constant 2; push local 0; call Math.multiply 2; add; pop local 0");
+        assert_eq!(body("let x++; let x--; let x /= 3; let x |= 1;"),
+            "push local 0; push constant 1; add; pop local 0; push local 0; push constant 1; sub; pop local 0; \
+             push local 0; push constant 3; call Math.divide 2; pop local 0; push local 0; push constant 1; or; pop local 0");
+        // the element address is computed once and kept on the stack
+        assert_eq!(body("let x[x + 1] -= x[0];"),
+            "push local 0; push constant 1; add; push local 0; add; pop pointer 1; push pointer 1; push that 0; \
+             push constant 0; push local 0; add; pop pointer 1; push that 0; sub; \
+             pop temp 0; pop pointer 1; push temp 0; pop that 0");
+        assert_eq!(body("for (x = 0; x < 2; x++) { }"),
+            "push constant 0; pop local 0; goto FOR_COND0; label WHILE_EXP0; push local 0; push constant 1; add; pop local 0; \
+             label FOR_COND0; push local 0; push constant 2; lt; not; if-goto WHILE_END0; goto WHILE_EXP0; label WHILE_END0");
+        assert_eq!(compile_main("let x += 1;"), Err(String::from("4:13: = expected, found Plus")));
+        // x[1] -= x[0] with x at address 3000
+        let vm = compile_main_with(&ext, "let x = 3000; let x[0] = 5; let x[1] = 12; let x[x[0] - 4] -= x[0];").unwrap();
+        let mut vm = Vm::new(Program::parse(&[(String::from("Main.vm"), vm)]).unwrap()).unwrap();
+        while !vm.halted {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.ram[3001], 7);
+    }
+
+    #[test]
+    fn test_string_constants() {
+        let string = |codes: &[u32]| {
+            let mut vm = format!("push constant {}\ncall String.new 1\n", codes.len());
+            for c in codes {
+                vm.push_str(&format!("push constant {}\ncall String.appendChar 2\n", c));
+            }
+            format!("function Main.main 1\n{}pop local 0\npush constant 0\nreturn\n", vm)
+        };
+        assert_eq!(compile_main("let x = \"A ~\";").unwrap(), string(&[65, 32, 126]));
+        assert_eq!(compile_main("let x = \"\u{80}\u{98}\";").unwrap(), string(&[128, 152]));
+        assert_eq!(compile_main("let x = \"caf\u{e9}!\";"),
+            Err(String::from("4:21: character '\u{e9}' (U+00E9) is not in the Jack character set")));
+        assert_eq!(compile_main("let x = \"a\tb\";"),
+            Err(String::from("4:19: character '\\t' (U+0009) is not in the Jack character set")));
+
+        let raw = Options { raw_chars: true, ..Options::default() };
+        assert_eq!(compile_main_with(&raw, "let x = \"caf\u{e9}\";").unwrap(), string(&[99, 97, 102, 233]));
+        assert_eq!(compile_main_with(&raw, "let x = \"\u{1F600}\";"),
+            Err(String::from("4:18: character '\u{1F600}' (U+1F600) does not fit in a Jack character code")));
+
+        let ext = Options { extensions: true, ..Options::default() };
+        assert_eq!(compile_main_with(&ext, r#"let x = "\"\\\n\x8C";"#).unwrap(), string(&[34, 92, 128, 140]));
+    }
+}
+
 /*
 #[cfg(test)]
 mod tests {
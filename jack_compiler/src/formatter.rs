@@ -0,0 +1,264 @@
+use crate::compiler::catch_panic;
+use crate::keyword::*;
+use crate::symbol::*;
+use crate::tokenizer::*;
+use crate::options::*;
+
+const INDENT: &str = "    ";
+
+/// Re-emits a Jack source with canonical layout: one statement per line, 4-space indentation,
+/// `{` at the end of the line that opens the block, `} else {` on one line, single spaces around
+/// binary operators and after commas, and at most one blank line in a row.
+/// Tokens are printed exactly as written and comments are kept where they were.
+/// `options` selects the syntax the source is lexed with, e.g. whether string escapes are allowed.
+pub fn format_source(src: &str, options: &Options) -> Result<String, String> {
+    let t = catch_panic(|| Tokenizer::with_options(src.as_bytes(), options))?;
+    if let Some(e) = t.errors.first() {
+        let span = e.span.unwrap_or_default();
+        return Err(format!("{} at line {}, column {}", e.message, span.line, span.col));
+    }
+    let mut items: Vec<Item> = t.source_tokens()
+        .map(|(tok, span)| Item::Token(tok.clone(), *span, t.lexeme(span).into_owned()))
+        .collect();
+    items.extend(t.comments.iter().map(|c| Item::Comment(c.clone())));
+    items.sort_by_key(|i| i.span().start);
+
+    let mut f = Formatter {
+        out: String::new(),
+        depth: 0,
+        paren_depth: 0,
+        line_start: true,
+        pending_newline: false,
+        line_comment: false,
+        prev: None,
+        prev_unary: false,
+        prev_end_line: 0,
+    };
+    for item in items.iter() {
+        match item {
+            Item::Token(tok, span, text) => f.token(tok, span, text),
+            Item::Comment(c) => f.comment(c),
+        }
+    }
+    if !f.out.ends_with('\n') {
+        f.out.push('\n');
+    }
+    Ok(f.out)
+}
+
+enum Item {
+    Token(Token, Span, String),
+    Comment(Comment),
+}
+
+impl Item {
+    fn span(&self) -> Span {
+        match self {
+            Item::Token(_, span, _) => *span,
+            Item::Comment(c) => c.span,
+        }
+    }
+}
+
+struct Formatter {
+    out: String,
+    depth: usize,
+    paren_depth: usize,
+    line_start: bool,
+    pending_newline: bool,
+    line_comment: bool, // the current line ends with a // comment
+    prev: Option<Token>,
+    prev_unary: bool,
+    prev_end_line: usize,
+}
+
+impl Formatter {
+    fn newline(&mut self, blank_line: bool) {
+        if !self.line_start {
+            self.out.push('\n');
+        }
+        if blank_line && !self.out.is_empty() && !self.out.ends_with("\n\n") && !self.out.ends_with("{\n") {
+            self.out.push('\n');
+        }
+        self.line_start = true;
+        self.pending_newline = false;
+        self.line_comment = false;
+    }
+
+    fn write(&mut self, text: &str, space_before: bool) {
+        if self.line_start {
+            for _ in 0..self.depth {
+                self.out.push_str(INDENT);
+            }
+        } else if space_before {
+            self.out.push(' ');
+        }
+        self.out.push_str(text);
+        self.line_start = false;
+    }
+
+    fn token(&mut self, tok: &Token, span: &Span, text: &str) {
+        let blank_line = span.line > self.prev_end_line + 1;
+        match tok {
+            Token::Symbol(Symbol::BraceR) => {
+                self.depth = self.depth.saturating_sub(1);
+                self.newline(false);
+            },
+            // `} else` is joined unless a line comment after the brace would swallow the `else`
+            Token::Keyword(Keyword::Else) if self.prev == Some(Token::Symbol(Symbol::BraceR)) && !self.line_comment => {
+                self.pending_newline = false;
+            },
+            _ => {
+                if self.pending_newline {
+                    self.newline(blank_line);
+                }
+            }
+        }
+        let space_before = self.space_between(tok);
+        self.write(text, space_before);
+        match tok {
+            Token::Symbol(Symbol::BraceL) => {
+                self.depth += 1;
+                self.pending_newline = true;
+            },
+            Token::Symbol(Symbol::BraceR) => {
+                self.pending_newline = true;
+            },
+            Token::Symbol(Symbol::SemiColon) if self.paren_depth == 0 => {
+                self.pending_newline = true;
+            },
+            Token::Symbol(Symbol::ParenL) => {
+                self.paren_depth += 1;
+            },
+            Token::Symbol(Symbol::ParenR) => {
+                self.paren_depth = self.paren_depth.saturating_sub(1);
+            },
+            _ => (),
+        }
+        self.prev_unary = *tok == Token::Symbol(Symbol::Minus) && !self.prev.as_ref().is_some_and(ends_operand);
+        self.prev = Some(tok.clone());
+        self.prev_end_line = span.line;
+    }
+
+    fn comment(&mut self, c: &Comment) {
+        let trailing = c.span.line == self.prev_end_line && self.prev.is_some();
+        let is_line_comment = c.text.starts_with("//");
+        if trailing {
+            self.write(&c.text, true);
+        } else {
+            let blank_line = c.span.line > self.prev_end_line + 1 && self.prev_end_line > 0;
+            if self.pending_newline || !self.line_start || blank_line {
+                self.newline(blank_line);
+            }
+            self.write(&reindent(&c.text, self.depth), false);
+            if !is_line_comment {
+                self.pending_newline = true;
+            }
+        }
+        if is_line_comment {
+            self.pending_newline = true;
+            self.line_comment = true;
+        }
+        self.prev_end_line = c.span.line + c.text.matches('\n').count();
+    }
+
+    // whether `tok` is separated by a space from the previous token on the same line
+    fn space_between(&self, tok: &Token) -> bool {
+        let prev = match &self.prev {
+            Some(p) => p,
+            None => { return false; }
+        };
+        match tok {
+            Token::Symbol(Symbol::ParenR | Symbol::SqParR | Symbol::SemiColon | Symbol::Comma | Symbol::Dot) => { return false; },
+            Token::Symbol(Symbol::Increment | Symbol::Decrement) => { return false; },
+            Token::Symbol(Symbol::ParenL | Symbol::SqParL) if matches!(prev, Token::Identifier(_)) => { return false; },
+            _ => (),
+        }
+        match prev {
+            Token::Symbol(Symbol::ParenL | Symbol::SqParL | Symbol::Dot) => false,
+            Token::Symbol(Symbol::Not) => false,
+            Token::Symbol(Symbol::Minus) => !self.prev_unary,
+            _ => true,
+        }
+    }
+}
+
+// Tokens that can end an operand: a '-' right after one of them is binary, otherwise unary.
+fn ends_operand(tok: &Token) -> bool {
+    matches!(tok,
+        Token::Identifier(_) | Token::IntConst(_) | Token::StringConst(_) |
+        Token::Keyword(Keyword::True | Keyword::False | Keyword::Null | Keyword::This) |
+        Token::Symbol(Symbol::ParenR | Symbol::SqParR))
+}
+
+// Block comments on their own line are re-indented when every continuation line starts with '*'.
+fn reindent(text: &str, depth: usize) -> String {
+    let mut lines = text.lines();
+    let first = lines.next().unwrap_or("").to_string();
+    let rest: Vec<&str> = lines.map(|l| l.trim()).collect();
+    if rest.is_empty() || !rest.iter().all(|l| l.starts_with('*')) {
+        return text.to_string();
+    }
+    let mut s = first;
+    for l in rest {
+        s.push('\n');
+        for _ in 0..depth {
+            s.push_str(INDENT);
+        }
+        s.push(' ');
+        s.push_str(l);
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn lexemes(src: &str) -> Vec<String> {
+        let t = Tokenizer::new(src.as_bytes());
+        t.source_tokens().map(|(_, s)| t.lexeme(s).into_owned()).collect()
+    }
+
+    #[test]
+    fn test_format_layout() {
+        let src = "class Main{\nfunction void main(){var int x;let x=-1+(2*x);\nif(x<0){do Output.printInt(x);}else{let x=~x;}\n\n\n   // done\nreturn;}}";
+        let expected = "class Main {\n    function void main() {\n        var int x;\n        let x = -1 + (2 * x);\n        if (x < 0) {\n            do Output.printInt(x);\n        } else {\n            let x = ~x;\n        }\n\n        // done\n        return;\n    }\n}\n";
+        assert_eq!(format_source(src, &Options::default()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_comments() {
+        let src = "/** Doc\n   * comment */\nclass A { // trailing\n  field int x; /* inline */\n}\n";
+        let expected = "/** Doc\n * comment */\nclass A { // trailing\n    field int x; /* inline */\n}\n";
+        assert_eq!(format_source(src, &Options::default()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_else_after_line_comment() {
+        let src = "class A {\n  function void f(int x) {\n    if (x > 0) {\n      let x = 1;\n    } // positive\n    else {\n      let x = 2;\n    }\n    if (x) {\n    }\n    // none\n    else {\n    }\n    return;\n  }\n}\n";
+        let formatted = format_source(src, &Options::default()).unwrap();
+        assert_eq!(lexemes(src), lexemes(&formatted));
+        assert!(formatted.contains("        } // positive\n        else {\n"), "{}", formatted);
+        assert_eq!(format_source(&formatted, &Options::default()).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_sample_programs() {
+        // formatting keeps the token sequence and is idempotent
+        for dir in ["./tests", "./jack"] {
+            for d in Path::new(dir).read_dir().unwrap().flatten() {
+                for f in d.path().read_dir().unwrap().flatten() {
+                    if f.path().extension().is_some_and(|e| e == "jack") {
+                        let src = fs::read_to_string(f.path()).unwrap();
+                        let formatted = format_source(&src, &Options::default()).unwrap();
+                        assert_eq!(lexemes(&src), lexemes(&formatted), "{}", f.path().display());
+                        assert_eq!(format_source(&formatted, &Options::default()).unwrap(), formatted, "{}", f.path().display());
+                    }
+                }
+            }
+        }
+    }
+}
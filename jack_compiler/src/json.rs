@@ -0,0 +1,281 @@
+use std::fmt;
+
+/// Minimal JSON value used for the LSP wire format.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(s: &str) -> Result<Json, String> {
+        let mut p = Parser { src: s.as_bytes(), pos: 0 };
+        let v = p.parse_value()?;
+        p.skip_whitespace();
+        if p.pos != p.src.len() {
+            return Err(format!("trailing characters at {}", p.pos));
+        }
+        Ok(v)
+    }
+
+    pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+        Json::Object(pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null      => write!(f, "null"),
+            Json::Bool(b)   => write!(f, "{}", b),
+            Json::Number(n) => {
+                if n.fract() == 0.0 && n.abs() < 1e15 {
+                    write!(f, "{}", *n as i64)
+                } else {
+                    write!(f, "{}", n)
+                }
+            },
+            Json::String(s) => write_string(f, s),
+            Json::Array(a)  => {
+                write!(f, "[")?;
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(pairs) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"'  => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.src.len() && self.src[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.src.get(self.pos) == Some(&b) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("'{}' expected at {}", b as char, self.pos))
+        }
+    }
+
+    fn parse_literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if self.src[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(format!("unexpected character at {}", self.pos))
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.src.get(self.pos) {
+            Some(b'n') => self.parse_literal("null", Json::Null),
+            Some(b't') => self.parse_literal("true", Json::Bool(true)),
+            Some(b'f') => self.parse_literal("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::String(self.parse_string()?)),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = vec![];
+                self.skip_whitespace();
+                if self.src.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.parse_value()?);
+                    self.skip_whitespace();
+                    match self.src.get(self.pos) {
+                        Some(b',') => { self.pos += 1; },
+                        Some(b']') => { self.pos += 1; break; },
+                        _ => { return Err(format!("',' or ']' expected at {}", self.pos)); }
+                    }
+                }
+                Ok(Json::Array(items))
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let mut pairs = vec![];
+                self.skip_whitespace();
+                if self.src.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(pairs));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.parse_string()?;
+                    self.expect(b':')?;
+                    let value = self.parse_value()?;
+                    pairs.push((key, value));
+                    self.skip_whitespace();
+                    match self.src.get(self.pos) {
+                        Some(b',') => { self.pos += 1; },
+                        Some(b'}') => { self.pos += 1; break; },
+                        _ => { return Err(format!("',' or '}}' expected at {}", self.pos)); }
+                    }
+                }
+                Ok(Json::Object(pairs))
+            },
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.src.get(self.pos) {
+                    self.pos += 1;
+                }
+                let s = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+                s.parse::<f64>().map(Json::Number).map_err(|e| format!("bad number {}: {}", s, e))
+            },
+            _ => Err(format!("unexpected character at {}", self.pos)),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        if self.src.get(self.pos) != Some(&b'"') {
+            return Err(format!("string expected at {}", self.pos));
+        }
+        self.pos += 1;
+        let mut bytes = vec![];
+        loop {
+            match self.src.get(self.pos) {
+                Some(b'"') => { self.pos += 1; break; },
+                Some(b'\\') => {
+                    let esc = self.src.get(self.pos + 1).copied();
+                    self.pos += 2;
+                    match esc {
+                        Some(b'"')  => bytes.push(b'"'),
+                        Some(b'\\') => bytes.push(b'\\'),
+                        Some(b'/')  => bytes.push(b'/'),
+                        Some(b'b')  => bytes.push(0x08),
+                        Some(b'f')  => bytes.push(0x0c),
+                        Some(b'n')  => bytes.push(b'\n'),
+                        Some(b'r')  => bytes.push(b'\r'),
+                        Some(b't')  => bytes.push(b'\t'),
+                        Some(b'u')  => {
+                            let hex = self.src.get(self.pos..self.pos + 4).ok_or("truncated \\u escape")?;
+                            let code = u32::from_str_radix(std::str::from_utf8(hex).unwrap_or(""), 16)
+                                .map_err(|_| format!("bad \\u escape at {}", self.pos))?;
+                            self.pos += 4;
+                            let c = char::from_u32(code).unwrap_or('\u{fffd}');
+                            let mut buf = [0; 4];
+                            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        },
+                        _ => { return Err(format!("bad escape at {}", self.pos)); }
+                    }
+                },
+                Some(&b) => { bytes.push(b); self.pos += 1; },
+                None => { return Err(String::from("unterminated string")); }
+            }
+        }
+        String::from_utf8(bytes).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let src = r#"{"jsonrpc":"2.0","id":1,"params":{"text":"a\"b\nc","list":[true,false,null,-1.5]}}"#;
+        let v = Json::parse(src).unwrap();
+        assert_eq!(v.get("id").and_then(|i| i.as_usize()), Some(1));
+        assert_eq!(v.get("params").and_then(|p| p.get("text")).and_then(|t| t.as_str()), Some("a\"b\nc"));
+        assert_eq!(v.to_string(), src);
+    }
+}
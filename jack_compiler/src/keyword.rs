@@ -33,6 +33,18 @@ pub enum Keyword {
     False,
     Null,
     This,
+    // extensions
+    Const,
+    For,
+    Break,
+    Continue,
+}
+
+impl Keyword {
+    /// Whether the keyword only exists with the language extensions; otherwise it is an ordinary identifier.
+    pub fn is_extension(&self) -> bool {
+        matches!(self, Keyword::Const | Keyword::For | Keyword::Break | Keyword::Continue)
+    }
 }
 
 impl fmt::Display for Keyword {
@@ -59,6 +71,10 @@ impl fmt::Display for Keyword {
             Keyword::False       => write!(f, "false"),
             Keyword::Null        => write!(f, "null"),
             Keyword::This        => write!(f, "this"),
+            Keyword::Const       => write!(f, "const"),
+            Keyword::For         => write!(f, "for"),
+            Keyword::Break       => write!(f, "break"),
+            Keyword::Continue    => write!(f, "continue"),
         }
     }
 }
@@ -89,6 +105,10 @@ impl FromStr for Keyword {
             "false"       => Ok(Keyword::False),
             "null"        => Ok(Keyword::Null),
             "this"        => Ok(Keyword::This),
+            "const"       => Ok(Keyword::Const),
+            "for"         => Ok(Keyword::For),
+            "break"       => Ok(Keyword::Break),
+            "continue"    => Ok(Keyword::Continue),
             _             => Err(BadKeywordError),
         }
     }
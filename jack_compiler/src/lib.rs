@@ -0,0 +1,23 @@
+pub mod tokenizer;
+pub mod engine;
+pub mod keyword;
+pub mod symbol;
+//pub mod analyzer;
+pub mod compiler;
+pub mod symbol_table;
+pub mod vm_writer;
+pub mod diagnostic;
+pub mod declaration;
+pub mod json;
+pub mod lsp;
+pub mod formatter;
+pub mod lint;
+pub mod doc;
+pub mod options;
+pub mod constant;
+pub mod registry;
+pub mod symbols;
+pub mod source_map;
+pub mod vm;
+pub mod debugger;
+pub mod profiler;
@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use crate::declaration::*;
+use crate::tokenizer::*;
+
+#[derive(Debug, Clone)]
+pub struct BadLintError(pub String);
+
+impl fmt::Display for BadLintError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown lint rule {}", self.0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UnusedLocal,
+    UnusedArgument,
+    UnusedField,
+    ReadBeforeAssign,
+    ShadowedField,
+    DiscardedReturn,
+    MissingReturn,
+    ConstructorReturn,
+    Naming,
+    MixedPrecedence,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 10] = [
+        Lint::UnusedLocal,
+        Lint::UnusedArgument,
+        Lint::UnusedField,
+        Lint::ReadBeforeAssign,
+        Lint::ShadowedField,
+        Lint::DiscardedReturn,
+        Lint::MissingReturn,
+        Lint::ConstructorReturn,
+        Lint::Naming,
+        Lint::MixedPrecedence,
+    ];
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lint::UnusedLocal       => write!(f, "unused-local"),
+            Lint::UnusedArgument    => write!(f, "unused-argument"),
+            Lint::UnusedField       => write!(f, "unused-field"),
+            Lint::ReadBeforeAssign  => write!(f, "read-before-assign"),
+            Lint::ShadowedField     => write!(f, "shadowed-field"),
+            Lint::DiscardedReturn   => write!(f, "discarded-return"),
+            Lint::MissingReturn     => write!(f, "missing-return"),
+            Lint::ConstructorReturn => write!(f, "constructor-return"),
+            Lint::Naming            => write!(f, "naming"),
+            Lint::MixedPrecedence   => write!(f, "mixed-precedence"),
+        }
+    }
+}
+
+impl FromStr for Lint {
+    type Err = BadLintError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Lint::ALL.iter().find(|l| l.to_string() == s).copied().ok_or_else(|| BadLintError(s.to_string()))
+    }
+}
+
+/// Which rules are enabled. Every rule is on by default; a config file turns them off or on with
+/// one `rule = allow` or `rule = warn` line per rule (`#` starts a comment).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LintConfig {
+    disabled: HashSet<Lint>,
+}
+
+impl LintConfig {
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let mut config = LintConfig::default();
+        for (n, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (rule, level) = line.split_once('=').ok_or_else(|| format!("line {}: 'rule = allow|warn' expected", n+1))?;
+            let lint = Lint::from_str(rule.trim()).map_err(|e| format!("line {}: {}", n+1, e))?;
+            match level.trim() {
+                "allow" => { config.disabled.insert(lint); },
+                "warn"  => { config.disabled.remove(&lint); },
+                l => { return Err(format!("line {}: 'allow' or 'warn' expected, found {}", n+1, l)); }
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let src = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        LintConfig::parse(&src).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn is_enabled(&self, lint: Lint) -> bool {
+        !self.disabled.contains(&lint)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Scope {
+    File,
+    Lines(usize, usize),    // first and last line
+    Subroutine(usize, usize), // byte range of the declaration
+}
+
+/// Rules silenced in the source by `// jack-lint: allow(rule, ...)` comments.
+/// Such a comment before the class applies to the whole file, one on the line before a subroutine
+/// declaration applies to the whole subroutine, and any other applies to its own line and the next.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Suppressions {
+    allowed: Vec<(Lint, Scope)>,
+}
+
+impl Suppressions {
+    pub fn from_comments(comments: &[Comment], class: Option<&ClassDecl>) -> Self {
+        let mut allowed = vec![];
+        for c in comments.iter() {
+            let rules = match c.text.split_once("jack-lint:").map(|(_, r)| r.trim()) {
+                Some(r) if r.starts_with("allow(") => &r["allow(".len()..r.find(')').unwrap_or(r.len())],
+                _ => { continue; }
+            };
+            let line = c.span.line;
+            let scope = match class {
+                Some(cls) if c.span.start < cls.range.start => Scope::File,
+                Some(cls) => {
+                    match cls.subroutines.iter().find(|s| s.range.line == line + 1) {
+                        Some(s) => Scope::Subroutine(s.range.start, s.range.end),
+                        None => Scope::Lines(line, line + 1),
+                    }
+                },
+                None => Scope::Lines(line, line + 1),
+            };
+            for r in rules.split(',') {
+                if let Ok(lint) = Lint::from_str(r.trim()) {
+                    allowed.push((lint, scope.clone()));
+                }
+            }
+        }
+        Suppressions { allowed }
+    }
+
+    pub fn is_allowed(&self, lint: Lint, span: Span) -> bool {
+        self.allowed.iter().any(|(l, scope)| *l == lint && match scope {
+            Scope::File => true,
+            Scope::Lines(first, last) => *first <= span.line && span.line <= *last,
+            Scope::Subroutine(start, end) => *start <= span.start && span.start < *end,
+        })
+    }
+}
+
+pub fn is_upper_camel_case(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase()) && !name.contains('_')
+}
+
+pub fn is_lower_camel_case(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase()) && !name.contains('_')
+}
+
+pub fn is_upper_snake_case(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase()) && !name.contains(|c: char| c.is_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::engine::*;
+
+    fn lint(src: &str, config: LintConfig) -> Vec<String> {
+        let mut e = Engine::new(Tokenizer::new(src.as_bytes()), io::sink());
+        e.enable_lints(config);
+        e.compile();
+        e.diagnostics().iter().map(|d| format!("{}: {}", d.span.unwrap().line, d.message)).collect()
+    }
+
+    const SAMPLE: &str = "class Main {
+    field int count, unused;
+    constructor Main new() {
+        return 0;
+    }
+    method int get() {
+        return count;
+    }
+    method void run(int step, int Extra) {
+        var int count, sum, never;
+        let sum = sum + step;
+        do get();
+        if (step) { return; }
+    }
+}
+";
+
+    #[test]
+    fn test_lint_rules() {
+        let warnings = lint(SAMPLE, LintConfig::default());
+        assert_eq!(warnings, vec![
+            "4: constructor should return this [constructor-return]",
+            "9: variable name Extra should be lowerCamelCase [naming]",
+            "10: var count shadows a class variable [shadowed-field]",
+            "12: value of type int returned by Main.get is discarded [discarded-return]",
+            "14: Main.run does not end with a return statement [missing-return]",
+            "9: arg Extra is never used [unused-argument]",
+            "10: var count is never used [unused-local]",
+            "10: var never is never used [unused-local]",
+            "11: local sum is read before it is assigned [read-before-assign]",
+            "2: field unused is never used [unused-field]",
+        ]);
+    }
+
+    #[test]
+    fn test_mixed_precedence() {
+        let src = "class Main {
+    function int f(int a, int b) {
+        var int c;
+        let c = a * b + 1;
+        let c = a + (b * 2) - 1;
+        let c = a + b * 2;
+        let c = (a & 1) = 0;
+        let c = a & 1 = 0;
+        let c = a = b | (c < 1);
+        return c;
+    }
+}
+";
+        assert_eq!(lint(src, LintConfig::default()), vec![
+            "6: '+' is applied before '*' here, unlike in C; add parentheses [mixed-precedence]",
+            "8: '&' is applied before '=' here, unlike in C; add parentheses [mixed-precedence]",
+        ]);
+    }
+
+    #[test]
+    fn test_lint_config_and_allow_comments() {
+        let config = LintConfig::parse("# quiet\nunused-local = allow\nnaming=allow\n").unwrap();
+        assert!(!config.is_enabled(Lint::UnusedLocal));
+        assert!(config.is_enabled(Lint::UnusedField));
+        assert!(LintConfig::parse("bogus = allow").is_err());
+
+        let src = SAMPLE
+            .replace("    method void run", "    // jack-lint: allow(missing-return, shadowed-field)\n    method void run")
+            .replace("do get();", "do get(); // jack-lint: allow(discarded-return)");
+        let src = format!("// jack-lint: allow(unused-field)\n{}", src);
+        let warnings = lint(&src, config);
+        assert_eq!(warnings, vec![
+            "5: constructor should return this [constructor-return]",
+            "11: arg Extra is never used [unused-argument]",
+            "13: local sum is read before it is assigned [read-before-assign]",
+        ]);
+    }
+}
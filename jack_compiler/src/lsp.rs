@@ -0,0 +1,575 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use crate::compiler::catch_panic;
+use crate::declaration::*;
+use crate::diagnostic::*;
+use crate::engine::*;
+use crate::json::*;
+use crate::keyword::*;
+use crate::symbol::*;
+use crate::symbol_table::*;
+use crate::registry::*;
+use crate::tokenizer::*;
+
+/// Serves the Language Server Protocol over `input`/`output` (stdio in `jack-lsp`) until `exit`.
+/// Documents are fully re-analyzed on every request: the tokenizer and the declaration outline give
+/// the structure, and a run of the engine into a sink gives the diagnostics and resolved variables.
+pub fn serve<R: BufRead, W: Write>(mut input: R, output: W) {
+    let mut server = Server {
+        out: output,
+        docs: HashMap::new(),
+    };
+    while let Some(body) = read_message(&mut input) {
+        if let Ok(msg) = Json::parse(&body) {
+            if !server.handle(&msg) {
+                break;
+            }
+        }
+    }
+}
+
+fn read_message<R: BufRead>(input: &mut R) -> Option<String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(v) = line.strip_prefix("Content-Length:") {
+            length = v.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    String::from_utf8(body).ok()
+}
+
+/// What the server knows about one document.
+struct Analysis {
+    tokens: Vec<(Token, Span)>,
+    class: Option<ClassDecl>,
+    refs: Vec<SymbolRef>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Analysis {
+    fn new(text: &str) -> Self {
+        let mut a = Analysis {
+            tokens: vec![],
+            class: None,
+            refs: vec![],
+            diagnostics: vec![],
+        };
+        match catch_panic(|| Tokenizer::new(text.as_bytes())) {
+            Ok(t) => {
+                a.tokens = t.source_tokens().map(|(t, s)| (t.clone(), *s)).collect();
+                a.class = ClassDecl::parse(&t);
+                a.diagnostics.extend(t.errors.iter().cloned());
+                let mut e = Engine::new(t, io::sink());
+                e.set_registry(Registry::os());
+                let result = catch_panic(|| e.compile());
+                a.diagnostics.extend(e.diagnostics().iter().cloned());
+                if let Err(msg) = result {
+                    a.diagnostics.push(Diagnostic::error(e.current_span(), msg));
+                }
+                a.refs = e.symbol_refs().to_vec();
+            },
+            Err(msg) => {
+                a.diagnostics.push(Diagnostic::error(None, msg));
+            }
+        }
+        a
+    }
+
+    // index of the token under the cursor, preferring the token starting there over the one ending there
+    fn token_at(&self, offset: usize) -> Option<usize> {
+        self.tokens.iter().position(|(_, s)| s.start <= offset && offset < s.end)
+            .or_else(|| self.tokens.iter().position(|(_, s)| s.end == offset))
+    }
+
+    fn ref_at(&self, span: Span) -> Option<&SymbolRef> {
+        self.refs.iter().find(|r| r.span == span)
+    }
+
+    /// Class named by the receiver token at `i` of a `receiver.name` expression,
+    /// and whether the receiver is an object (a variable or `this`) rather than the class itself.
+    fn receiver_class(&self, i: usize) -> Option<(String, bool)> {
+        let (tok, span) = &self.tokens[i];
+        let name = match tok {
+            Token::Identifier(name) => name,
+            Token::Keyword(Keyword::This) => {
+                return self.class.as_ref().map(|c| (c.name.clone(), true));
+            },
+            _ => { return None; }
+        };
+        let var_type = self.ref_at(*span)
+            .or_else(|| self.refs.iter().rev().find(|r| &r.name == name && r.span.start < span.start))
+            .map(|r| r.var_type.clone())
+            .or_else(|| self.class.as_ref().and_then(|c| c.var(name)).map(|v| v.var_type.clone()));
+        match var_type {
+            Some(VarType::ClassName(c)) => Some((c, true)),
+            Some(_) => None,
+            None => Some((name.clone(), false)),
+        }
+    }
+}
+
+struct Server<W: Write> {
+    out: W,
+    docs: HashMap<String, String>,
+}
+
+impl<W: Write> Server<W> {
+    fn handle(&mut self, msg: &Json) -> bool {
+        let method = match msg.get("method").and_then(|m| m.as_str()) {
+            Some(m) => m,
+            None => { return true; } // a response to one of our requests
+        };
+        let id = msg.get("id").cloned();
+        let params = msg.get("params").cloned().unwrap_or(Json::Null);
+        match method {
+            "initialize" => {
+                let result = Json::object(vec![
+                    ("capabilities", Json::object(vec![
+                        ("textDocumentSync", 1.into()),
+                        ("definitionProvider", true.into()),
+                        ("hoverProvider", true.into()),
+                        ("completionProvider", Json::object(vec![("triggerCharacters", Json::Array(vec![".".into()]))])),
+                        ("documentSymbolProvider", true.into()),
+                    ])),
+                    ("serverInfo", Json::object(vec![("name", "jack-lsp".into())])),
+                ]);
+                self.respond(id, result);
+            },
+            "shutdown" => {
+                self.respond(id, Json::Null);
+            },
+            "exit" => {
+                return false;
+            },
+            "textDocument/didOpen" => {
+                let doc = params.get("textDocument");
+                let uri = doc.and_then(|d| d.get("uri")).and_then(|u| u.as_str());
+                let text = doc.and_then(|d| d.get("text")).and_then(|t| t.as_str());
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.docs.insert(uri.to_string(), text.to_string());
+                    self.publish_diagnostics(uri);
+                }
+            },
+            "textDocument/didChange" => {
+                let uri = params.get("textDocument").and_then(|d| d.get("uri")).and_then(|u| u.as_str());
+                let text = params.get("contentChanges").and_then(|c| c.as_array())
+                    .and_then(|c| c.last()).and_then(|c| c.get("text")).and_then(|t| t.as_str());
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.docs.insert(uri.to_string(), text.to_string());
+                    self.publish_diagnostics(uri);
+                }
+            },
+            "textDocument/didClose" => {
+                if let Some(uri) = params.get("textDocument").and_then(|d| d.get("uri")).and_then(|u| u.as_str()) {
+                    self.docs.remove(uri);
+                    self.notify("textDocument/publishDiagnostics", Json::object(vec![
+                        ("uri", uri.into()),
+                        ("diagnostics", Json::Array(vec![])),
+                    ]));
+                }
+            },
+            "textDocument/definition" => {
+                let result = self.definition(&params).unwrap_or(Json::Null);
+                self.respond(id, result);
+            },
+            "textDocument/hover" => {
+                let result = self.hover(&params).unwrap_or(Json::Null);
+                self.respond(id, result);
+            },
+            "textDocument/completion" => {
+                let result = self.completion(&params).unwrap_or(Json::Array(vec![]));
+                self.respond(id, result);
+            },
+            "textDocument/documentSymbol" => {
+                let result = self.document_symbols(&params).unwrap_or(Json::Array(vec![]));
+                self.respond(id, result);
+            },
+            _ => {
+                if let Some(id) = id {
+                    self.send(Json::object(vec![
+                        ("jsonrpc", "2.0".into()),
+                        ("id", id),
+                        ("error", Json::object(vec![
+                            ("code", Json::Number(-32601.0)),
+                            ("message", format!("method not found: {}", method).into()),
+                        ])),
+                    ]));
+                }
+            }
+        }
+        true
+    }
+
+    fn send(&mut self, msg: Json) {
+        let body = msg.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.out.flush().unwrap();
+    }
+
+    fn respond(&mut self, id: Option<Json>, result: Json) {
+        self.send(Json::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("id", id.unwrap_or(Json::Null)),
+            ("result", result),
+        ]));
+    }
+
+    fn notify(&mut self, method: &str, params: Json) {
+        self.send(Json::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params),
+        ]));
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) {
+        let text = self.docs[uri].clone();
+        let diagnostics = Analysis::new(&text).diagnostics.iter().map(|d| {
+            let related = d.notes.iter().map(|(span, message)| Json::object(vec![
+                ("location", Json::object(vec![("uri", uri.into()), ("range", range(&text, *span))])),
+                ("message", message.clone().into()),
+            ])).collect();
+            Json::object(vec![
+                ("range", range(&text, d.span.unwrap_or_default())),
+                ("severity", (if d.severity == Severity::Error { 1 } else { 2 }).into()),
+                ("source", "jackc".into()),
+                ("message", d.message.clone().into()),
+                ("relatedInformation", Json::Array(related)),
+            ])
+        }).collect();
+        self.notify("textDocument/publishDiagnostics", Json::object(vec![
+            ("uri", uri.into()),
+            ("diagnostics", Json::Array(diagnostics)),
+        ]));
+    }
+
+    // (uri, text, cursor offset) of a TextDocumentPositionParams
+    fn document_position(&self, params: &Json) -> Option<(String, String, usize)> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let text = self.document_text(uri)?;
+        let pos = params.get("position")?;
+        let offset = offset_of(&text, pos.get("line")?.as_usize()?, pos.get("character")?.as_usize()?);
+        Some((uri.to_string(), text, offset))
+    }
+
+    fn document_text(&self, uri: &str) -> Option<String> {
+        match self.docs.get(uri) {
+            Some(text) => Some(text.clone()),
+            None => fs::read_to_string(uri_to_path(uri)).ok(),
+        }
+    }
+
+    /// Looks up a class by name: first the document itself, then the other open documents,
+    /// then the .jack files in the same directory. Returns (uri, text, outline).
+    fn find_class(&self, uri: &str, name: &str) -> Option<(String, String, ClassDecl)> {
+        let mut candidates = vec![uri.to_string()];
+        candidates.extend(self.docs.keys().filter(|u| *u != uri).cloned());
+        if let Some(dir) = uri_to_path(uri).parent() {
+            if let Ok(entries) = dir.read_dir() {
+                let mut files: Vec<PathBuf> = entries.flatten().map(|e| e.path())
+                    .filter(|p| p.extension().is_some_and(|ext| ext == "jack"))
+                    .collect();
+                files.sort();
+                candidates.extend(files.iter().map(|p| path_to_uri(p)));
+            }
+        }
+        for u in candidates {
+            let text = match self.document_text(&u) {
+                Some(text) => text,
+                None => { continue; }
+            };
+            if let Ok(Some(class)) = catch_panic(|| ClassDecl::parse(&Tokenizer::new(text.as_bytes()))) {
+                if class.name == name {
+                    return Some((u, text, class));
+                }
+            }
+        }
+        None
+    }
+
+    fn definition(&self, params: &Json) -> Option<Json> {
+        let (uri, text, offset) = self.document_position(params)?;
+        let a = Analysis::new(&text);
+        let i = a.token_at(offset)?;
+        let (tok, span) = &a.tokens[i];
+        let name = match tok {
+            Token::Identifier(name) => name,
+            _ => { return None; }
+        };
+        if let Some(r) = a.ref_at(*span) {
+            if r.decl != Span::default() {
+                return Some(location(&uri, &text, r.decl));
+            }
+        }
+        // receiver '.' subroutineName
+        if i >= 2 && a.tokens[i-1].0 == Token::Symbol(Symbol::Dot) {
+            let (cls, _) = a.receiver_class(i-2)?;
+            let (u, t, c) = self.find_class(&uri, &cls)?;
+            return Some(location(&u, &t, c.subroutine(name)?.span));
+        }
+        // subroutineName '(' within its own class
+        if a.tokens.get(i+1).map(|(t, _)| t) == Some(&Token::Symbol(Symbol::ParenL)) {
+            let c = a.class.as_ref()?;
+            return Some(location(&uri, &text, c.subroutine(name)?.span));
+        }
+        // className
+        let (u, t, c) = self.find_class(&uri, name)?;
+        Some(location(&u, &t, c.span))
+    }
+
+    fn hover(&self, params: &Json) -> Option<Json> {
+        let (uri, text, offset) = self.document_position(params)?;
+        let a = Analysis::new(&text);
+        let i = a.token_at(offset)?;
+        let (tok, span) = &a.tokens[i];
+        let name = match tok {
+            Token::Identifier(name) => name,
+            _ => { return None; }
+        };
+        let contents = if let Some(r) = a.ref_at(*span) {
+            format!("```jack\n{} {} {}\n```\nindex {}", r.kind, r.var_type, r.name, r.index)
+        } else {
+            let sub = if i >= 2 && a.tokens[i-1].0 == Token::Symbol(Symbol::Dot) {
+                let (cls, _) = a.receiver_class(i-2)?;
+                self.find_class(&uri, &cls)?.2.subroutine(name)?.clone()
+            } else {
+                a.class.as_ref()?.subroutine(name)?.clone()
+            };
+            format!("```jack\n{}\n```", sub.signature())
+        };
+        Some(Json::object(vec![
+            ("contents", Json::object(vec![("kind", "markdown".into()), ("value", contents.into())])),
+            ("range", range(&text, *span)),
+        ]))
+    }
+
+    fn completion(&self, params: &Json) -> Option<Json> {
+        let (uri, text, offset) = self.document_position(params)?;
+        let a = Analysis::new(&text);
+        // the '.' right before the cursor, possibly followed by a partially typed name
+        let i = a.tokens.iter().rposition(|(_, s)| s.end <= offset)?;
+        let dot = match &a.tokens[i].0 {
+            Token::Symbol(Symbol::Dot) => i,
+            Token::Identifier(_) if i >= 1 && a.tokens[i-1].0 == Token::Symbol(Symbol::Dot) && a.tokens[i].1.end == offset => i-1,
+            _ => { return None; }
+        };
+        if dot == 0 {
+            return None;
+        }
+        let (cls, instance) = a.receiver_class(dot-1)?;
+        let (_, _, c) = self.find_class(&uri, &cls)?;
+        let items = c.subroutines.iter()
+            .filter(|s| (s.kind == Keyword::Method) == instance)
+            .map(|s| {
+                let kind: usize = match s.kind {
+                    Keyword::Method      => 2,
+                    Keyword::Constructor => 4,
+                    _                    => 3,
+                };
+                Json::object(vec![
+                    ("label", s.name.clone().into()),
+                    ("kind", kind.into()),
+                    ("detail", s.signature().into()),
+                ])
+            })
+            .collect();
+        Some(Json::Array(items))
+    }
+
+    fn document_symbols(&self, params: &Json) -> Option<Json> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let text = self.document_text(uri)?;
+        let class = Analysis::new(&text).class?;
+        let mut children = vec![];
+        for v in class.vars.iter() {
+            children.push(Json::object(vec![
+                ("name", v.name.clone().into()),
+                ("detail", format!("{} {}", v.kind, v.var_type).into()),
+                ("kind", (match v.kind { VarKind::Field => 8, VarKind::Const => 14, _ => 13 }).into()),
+                ("range", range(&text, v.span)),
+                ("selectionRange", range(&text, v.span)),
+            ]));
+        }
+        for s in class.subroutines.iter() {
+            let kind: usize = match s.kind {
+                Keyword::Method      => 6,
+                Keyword::Constructor => 9,
+                _                    => 12,
+            };
+            children.push(Json::object(vec![
+                ("name", s.name.clone().into()),
+                ("detail", s.signature().into()),
+                ("kind", kind.into()),
+                ("range", range(&text, s.range)),
+                ("selectionRange", range(&text, s.span)),
+            ]));
+        }
+        Some(Json::Array(vec![Json::object(vec![
+            ("name", class.name.clone().into()),
+            ("kind", 5.into()),
+            ("range", range(&text, class.range)),
+            ("selectionRange", range(&text, class.span)),
+            ("children", Json::Array(children)),
+        ])]))
+    }
+}
+
+// LSP positions are 0-based lines and UTF-16 code unit columns.
+fn position(text: &str, offset: usize) -> Json {
+    let offset = offset.min(text.len());
+    let line_start = text[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line = text[..offset].matches('\n').count();
+    let character = text[line_start..offset].encode_utf16().count();
+    Json::object(vec![("line", line.into()), ("character", character.into())])
+}
+
+fn offset_of(text: &str, line: usize, character: usize) -> usize {
+    let mut line_start = 0;
+    for _ in 0..line {
+        match text[line_start..].find('\n') {
+            Some(i) => { line_start += i + 1; },
+            None => { return text.len(); }
+        }
+    }
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn range(text: &str, span: Span) -> Json {
+    Json::object(vec![("start", position(text, span.start)), ("end", position(text, span.end))])
+}
+
+fn location(uri: &str, text: &str, span: Span) -> Json {
+    Json::object(vec![("uri", uri.into()), ("range", range(text, span))])
+}
+
+fn uri_to_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let mut bytes = vec![];
+    let mut it = path.bytes();
+    while let Some(b) = it.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = it.by_ref().take(2).collect();
+            if let Some(v) = std::str::from_utf8(&hex).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                bytes.push(v);
+                continue;
+            }
+        }
+        bytes.push(b);
+    }
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for b in path.to_string_lossy().bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => uri.push(b as char),
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(msg: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", msg.len(), msg)
+    }
+
+    fn run(messages: &[String]) -> Vec<Json> {
+        let input: String = messages.iter().map(|m| frame(m)).collect();
+        let mut output = vec![];
+        serve(input.as_bytes(), &mut output);
+        let mut reader = &output[..];
+        let mut replies = vec![];
+        while let Some(body) = read_message(&mut reader) {
+            replies.push(Json::parse(&body).unwrap());
+        }
+        replies
+    }
+
+    fn request(id: usize, method: &str, params: Json) -> String {
+        Json::object(vec![("jsonrpc", "2.0".into()), ("id", id.into()), ("method", method.into()), ("params", params)]).to_string()
+    }
+
+    fn at(uri: &str, line: usize, character: usize) -> Json {
+        Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", uri.into())])),
+            ("position", Json::object(vec![("line", line.into()), ("character", character.into())])),
+        ])
+    }
+
+    fn result_of(replies: &[Json], id: usize) -> Json {
+        replies.iter().find(|r| r.get("id").and_then(|i| i.as_usize()) == Some(id)).unwrap().get("result").unwrap().clone()
+    }
+
+    #[test]
+    fn test_lsp_session() {
+        let uri = "file:///nonexistent/Main.jack";
+        let text = "class Main {\n  field int count;\n  method void run(int step) {\n    var Main other;\n    let count = count + step;\n    do other.\n  }\n}\n";
+        let open = Json::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/didOpen".into()),
+            ("params", Json::object(vec![("textDocument", Json::object(vec![("uri", uri.into()), ("text", text.into())]))])),
+        ]).to_string();
+        let replies = run(&[
+            request(1, "initialize", Json::object(vec![])),
+            open,
+            request(2, "textDocument/definition", at(uri, 4, 26)),
+            request(3, "textDocument/hover", at(uri, 4, 9)),
+            request(4, "textDocument/completion", at(uri, 5, 13)),
+            request(5, "textDocument/documentSymbol", Json::object(vec![("textDocument", Json::object(vec![("uri", uri.into())]))])),
+            request(6, "shutdown", Json::Null),
+            String::from(r#"{"jsonrpc":"2.0","method":"exit"}"#),
+        ]);
+
+        let caps = result_of(&replies, 1);
+        assert_eq!(caps.get("capabilities").and_then(|c| c.get("hoverProvider")), Some(&Json::Bool(true)));
+
+        let diags = replies.iter().find(|r| r.get("method").and_then(|m| m.as_str()) == Some("textDocument/publishDiagnostics")).unwrap();
+        let diags = diags.get("params").unwrap().get("diagnostics").unwrap().as_array().unwrap();
+        assert_eq!(diags.len(), 1);
+
+        // `step` resolves to the parameter declared on line 2
+        let def = result_of(&replies, 2);
+        assert_eq!(def.get("range").unwrap().get("start").unwrap().to_string(), r#"{"line":2,"character":22}"#);
+
+        let hover = result_of(&replies, 3);
+        let value = hover.get("contents").unwrap().get("value").unwrap().as_str().unwrap();
+        assert!(value.contains("field int count"));
+        assert!(value.contains("index 0"));
+
+        let completion = result_of(&replies, 4);
+        let labels: Vec<&str> = completion.as_array().unwrap().iter().map(|c| c.get("label").unwrap().as_str().unwrap()).collect();
+        assert_eq!(labels, vec!["run"]);
+
+        let symbols = result_of(&replies, 5);
+        let class = &symbols.as_array().unwrap()[0];
+        assert_eq!(class.get("name").unwrap().as_str(), Some("Main"));
+        assert_eq!(class.get("children").unwrap().as_array().unwrap().len(), 2);
+    }
+}
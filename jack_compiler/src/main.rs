@@ -1,18 +1,241 @@
-mod tokenizer;
-mod engine;
-mod keyword;
-mod symbol;
-//mod analyzer;
-mod compiler;
-mod symbol_table;
-mod vm_writer;
-
 use std::env;
-use std::path::Path;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+use jack_compiler::compiler::{Compiler, Emit};
+use jack_compiler::options::Options;
+use jack_compiler::lint::LintConfig;
+use jack_compiler::doc::DocFormat;
+use jack_compiler::declaration::ClassDecl;
+use jack_compiler::tokenizer::Tokenizer;
+use jack_compiler::debugger::Debugger;
+use jack_compiler::profiler;
+use jack_compiler::source_map::{ProgramMap, SourceMap};
+use jack_compiler::vm::{Program, Vm};
+
+const USAGE: &str = "usage: jackc [--watch] [--emit vm,map,symbols] [--stubs <file>] [--annotate] [--extensions] [--precedence] [--raw-chars] <filename>.jack | <dirname>\n       jackc lint [--config <file>] [--stubs <file>] [--extensions] [--precedence] [--raw-chars] <filename>.jack | <dirname>\n       jackc doc [--format md|html] <filename>.jack | <dirname>\n       jackc debug [--stubs <file>] [--extensions] [--precedence] [--raw-chars] <filename>.jack | <dirname>\n       jackc profile [--steps <n>] [--folded <file>] [--stubs <file>] [--extensions] [--precedence] [--raw-chars] <filename>.jack | <dirname>";
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 { panic!("usage: jackc <filename>.jack | <dirname>"); }
-    let arg_path = Path::new(&args[1]);
-    compiler::Compiler::run(arg_path);
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|a| a.as_str()) {
+        Some("lint") => lint(&args[1..]),
+        Some("doc") => doc(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("profile") => profile(&args[1..]),
+        _ => (),
+    }
+    let mut watch = false;
+    let mut options = Options::default();
+    let mut stubs = vec![];
+    let mut emit = vec![Emit::Vm];
+    let mut source = None;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--watch" => { watch = true; },
+            "--emit" => {
+                emit = match it.next().map(|e| e.split(',').map(|k| k.parse::<Emit>()).collect()) {
+                    Some(Ok(e)) => e,
+                    Some(Err(e)) => { eprintln!("{}\n{}", e, USAGE); process::exit(2); },
+                    None => { eprintln!("--emit expects a list of outputs\n{}", USAGE); process::exit(2); }
+                };
+            },
+            "--stubs" => { stubs.extend(it.next().map(PathBuf::from)); },
+            a if parse_option(a, &mut options) => (),
+            a if a.starts_with("--") => { eprintln!("unknown option {}\n{}", a, USAGE); process::exit(2); },
+            a => { source = Some(a); }
+        }
+    }
+    let arg_path = match source {
+        Some(s) => Path::new(s),
+        None => { eprintln!("{}", USAGE); process::exit(2); }
+    };
+    let mut compiler = new_compiler(options, &stubs);
+    compiler.set_emit(emit);
+    if watch {
+        compiler.watch(arg_path, Duration::from_millis(500));
+    } else if !compiler.run(arg_path) {
+        process::exit(1);
+    }
+}
+
+// Language options shared by the compile and lint commands. Returns false if `arg` is not one of them.
+fn parse_option(arg: &str, options: &mut Options) -> bool {
+    match arg {
+        "--extensions" => { options.extensions = true; },
+        "--precedence" => { options.precedence = true; },
+        "--raw-chars" => { options.raw_chars = true; },
+        "--annotate" => { options.annotate = true; },
+        _ => { return false; }
+    }
+    true
+}
+
+// A compiler that knows the classes declared in the stub files.
+fn new_compiler(options: Options, stubs: &[PathBuf]) -> Compiler {
+    let mut compiler = Compiler::new(options);
+    for path in stubs {
+        if let Err(d) = compiler.load_stubs(path) {
+            eprintln!("{}", d);
+            process::exit(2);
+        }
+    }
+    compiler
+}
+
+// jackc lint: the configuration defaults to .jacklint next to the sources when present.
+fn lint(args: &[String]) -> ! {
+    let mut config_path = None;
+    let mut options = Options::default();
+    let mut stubs = vec![];
+    let mut source = None;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--config" => { config_path = it.next().map(PathBuf::from); },
+            "--stubs" => { stubs.extend(it.next().map(PathBuf::from)); },
+            a if parse_option(a, &mut options) => (),
+            a if a.starts_with("--") => { eprintln!("unknown option {}\n{}", a, USAGE); process::exit(2); },
+            a => { source = Some(Path::new(a)); }
+        }
+    }
+    let source = match source {
+        Some(s) => s,
+        None => { eprintln!("{}", USAGE); process::exit(2); }
+    };
+    let config_path = config_path.or_else(|| {
+        let dir = if source.is_dir() { source } else { source.parent().unwrap_or(Path::new(".")) };
+        Some(dir.join(".jacklint")).filter(|p| p.exists())
+    });
+    let config = match config_path {
+        Some(p) => LintConfig::load(&p).unwrap_or_else(|e| { eprintln!("{}", e); process::exit(2); }),
+        None => LintConfig::default(),
+    };
+    let compiler = new_compiler(options, &stubs);
+    let registry = compiler.registry(source);
+    let mut count = 0;
+    for f in Compiler::sources(source) {
+        for d in compiler.lint_file(&f, &registry, &config) {
+            println!("{}", d);
+            count += 1;
+        }
+    }
+    process::exit(if count > 0 { 1 } else { 0 });
+}
+
+// jackc doc: writes <ClassName>.md (or .html) next to each source file.
+fn doc(args: &[String]) -> ! {
+    let mut format = DocFormat::Markdown;
+    let mut source = None;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match it.next().map(|f| f.parse::<DocFormat>()) {
+                    Some(Ok(f)) => f,
+                    _ => { eprintln!("--format expects md or html\n{}", USAGE); process::exit(2); }
+                };
+            },
+            a if a.starts_with("--") => { eprintln!("unknown option {}\n{}", a, USAGE); process::exit(2); },
+            a => { source = Some(Path::new(a)); }
+        }
+    }
+    let source = match source {
+        Some(s) => s,
+        None => { eprintln!("{}", USAGE); process::exit(2); }
+    };
+    let mut ok = true;
+    for f in Compiler::sources(source) {
+        let class = fs::File::open(&f).ok().and_then(|fin| ClassDecl::parse(&Tokenizer::new(fin)));
+        match class {
+            Some(class) => {
+                let out = f.with_file_name(&class.name).with_extension(format.extension());
+                fs::write(&out, format.render(&class)).expect("cannot create output file");
+                println!("{}", out.display());
+            },
+            None => {
+                eprintln!("{}: error: no class declaration found", f.display());
+                ok = false;
+            }
+        }
+    }
+    process::exit(if ok { 0 } else { 1 });
+}
+
+// Compiles the sources with their source maps, returning the directory of the program.
+fn compile_with_maps<'a>(options: Options, stubs: &[PathBuf], source: &'a Path) -> &'a Path {
+    let mut compiler = new_compiler(options, stubs);
+    compiler.set_emit(vec![Emit::Map]);
+    if !compiler.run(source) {
+        process::exit(1);
+    }
+    if source.is_dir() { source } else { source.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new(".")) }
+}
+
+// jackc debug: compiles the sources with their source maps, then runs the .vm files of their directory,
+// which should include the OS ones, under the debugger.
+fn debug(args: &[String]) -> ! {
+    let mut options = Options::default();
+    let mut stubs = vec![];
+    let mut source = None;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--stubs" => { stubs.extend(it.next().map(PathBuf::from)); },
+            a if parse_option(a, &mut options) => (),
+            a if a.starts_with("--") => { eprintln!("unknown option {}\n{}", a, USAGE); process::exit(2); },
+            a => { source = Some(Path::new(a)); }
+        }
+    }
+    let source = match source {
+        Some(s) => s,
+        None => { eprintln!("{}", USAGE); process::exit(2); }
+    };
+    let dir = compile_with_maps(options, &stubs, source);
+    let mut debugger = Debugger::load(dir).unwrap_or_else(|e| { eprintln!("{}", e); process::exit(1); });
+    println!("type help for the commands");
+    debugger.repl(&mut io::stdin().lock(), &mut io::stdout()).expect("cannot use the terminal");
+    process::exit(0);
+}
+
+// jackc profile: compiles the sources with their source maps, runs the .vm files of their directory for at most
+// `--steps` instructions and prints where they were spent.
+fn profile(args: &[String]) -> ! {
+    let mut options = Options::default();
+    let mut stubs = vec![];
+    let mut steps = 10_000_000;
+    let mut folded = None;
+    let mut source = None;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--steps" => {
+                steps = it.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| {
+                    eprintln!("--steps expects a number of instructions\n{}", USAGE);
+                    process::exit(2);
+                });
+            },
+            "--folded" => { folded = it.next().map(PathBuf::from); },
+            "--stubs" => { stubs.extend(it.next().map(PathBuf::from)); },
+            a if parse_option(a, &mut options) => (),
+            a if a.starts_with("--") => { eprintln!("unknown option {}\n{}", a, USAGE); process::exit(2); },
+            a => { source = Some(Path::new(a)); }
+        }
+    }
+    let source = match source {
+        Some(s) => s,
+        None => { eprintln!("{}", USAGE); process::exit(2); }
+    };
+    let dir = compile_with_maps(options, &stubs, source);
+    let loaded = Program::load_dir(dir)
+        .and_then(|program| Ok((ProgramMap::new(&program, SourceMap::load_dir(dir)?)?, Vm::new(program)?)));
+    let (map, mut vm) = loaded.unwrap_or_else(|e| { eprintln!("{}", e); process::exit(1); });
+    let profile = profiler::profile(&mut vm, &map, steps);
+    print!("{}", profile.report(&vm.program));
+    if let Some(path) = folded {
+        fs::write(&path, profile.folded(&vm.program)).expect("cannot create the folded stacks file");
+    }
+    process::exit(if profile.error.is_some() { 1 } else { 0 });
+}
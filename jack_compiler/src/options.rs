@@ -0,0 +1,18 @@
+/// Language and output settings shared by the tokenizer, the engine and the command line drivers.
+/// The default is plain Jack as specified by the course.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options {
+    /// Accept the language extensions: escape sequences in string constants, character literals,
+    /// hexadecimal and binary integer constants, `_` digit separators, the `&&` and `||` operators,
+    /// `else if` chains, `for` loops, `break`/`continue`, compound assignments such as `+=` and `++`,
+    /// class-level `const` declarations, and `var` declarations inside any block.
+    pub extensions: bool,
+    /// Parse expressions with the usual operator precedence instead of strictly left to right.
+    pub precedence: bool,
+    /// Let string constants contain characters outside the Jack character set, emitted with their
+    /// Unicode code point as the character code.
+    pub raw_chars: bool,
+    /// Comment the VM code with the Jack line each statement comes from and the names of the variables
+    /// pushed and popped. Off by default, so that the output matches that of the reference compiler.
+    pub annotate: bool,
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use crate::source_map::*;
+use crate::vm::*;
+
+/// Instructions executed by a function, or located on a line of source.
+/// `exclusive` counts its own instructions, `inclusive` adds those of the subroutines it calls, counting recursive
+/// calls once. For a line, `calls` are the calls made from it; for a function, the calls to it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Cost {
+    pub calls: u64,
+    pub exclusive: u64,
+    pub inclusive: u64,
+}
+
+/// What a run of a program under `profile` cost.
+pub struct Profile {
+    pub total: u64,
+    pub halted: bool,
+    pub error: Option<String>,
+    pub functions: Vec<Cost>,              // indexed like `Program::functions`
+    pub lines: Vec<(String, usize, Cost)>, // Jack file and line
+    stacks: Vec<(Vec<usize>, u64)>,        // functions on the stack, outermost first, and the instructions run there
+}
+
+// A call in progress.
+struct Open {
+    function: usize,
+    entry: u64,          // instructions executed when it started
+    line: Option<usize>, // index of its call site in the line costs
+    node: usize,         // its call stack
+}
+
+/// Runs `vm` until the program halts, fails, or has executed `limit` instructions,
+/// attributing each instruction to its function and, through `map`, to its line of source.
+pub fn profile(vm: &mut Vm, map: &ProgramMap, limit: u64) -> Profile {
+    let program = &vm.program;
+    // lines get dense indices, looked up by instruction
+    let mut line_index = HashMap::new();
+    let mut line_keys = vec![];
+    let pc_lines: Vec<Option<usize>> = (0..program.code.len())
+        .map(|pc| map.location(program, pc).map(|(m, l)| *line_index.entry((m, l.line)).or_insert_with(|| {
+            line_keys.push((m, l.line));
+            line_keys.len() - 1
+        })))
+        .collect();
+    let mut functions = vec![Cost::default(); program.functions.len()];
+    let mut lines = vec![Cost::default(); line_keys.len()];
+    let mut active_functions = vec![0u32; functions.len()];
+    let mut active_lines = vec![0u32; lines.len()];
+    // call stacks as a tree: parent and function of each node, with the instructions run in it
+    let mut nodes: Vec<(Option<usize>, usize)> = vec![];
+    let mut children = HashMap::new();
+    let mut counts: Vec<u64> = vec![];
+    let mut node_of = |parent: Option<usize>, function: usize, counts: &mut Vec<u64>| {
+        *children.entry((parent, function)).or_insert_with(|| {
+            nodes.push((parent, function));
+            counts.push(0);
+            nodes.len() - 1
+        })
+    };
+
+    let mut open: Vec<Open> = vec![];
+    for frame in vm.frames.iter() {
+        let node = node_of(open.last().map(|o| o.node), frame.function, &mut counts);
+        functions[frame.function].calls += 1;
+        active_functions[frame.function] += 1;
+        open.push(Open { function: frame.function, entry: vm.steps, line: None, node });
+    }
+    let start = vm.steps;
+    let mut error = None;
+    while !vm.halted && vm.steps - start < limit {
+        let pc = vm.pc;
+        let depth = vm.frames.len();
+        let top = open.last().expect("no frame");
+        let (function, node) = (top.function, top.node);
+        if let Err(e) = vm.step() {
+            error = Some(e);
+            break;
+        }
+        functions[function].exclusive += 1;
+        counts[node] += 1;
+        let line = pc_lines[pc];
+        if let Some(l) = line {
+            lines[l].exclusive += 1;
+            if active_lines[l] == 0 {
+                lines[l].inclusive += 1;
+            }
+        }
+        if vm.frames.len() > depth {
+            let callee = vm.frames.last().expect("no frame").function;
+            functions[callee].calls += 1;
+            active_functions[callee] += 1;
+            if let Some(l) = line {
+                lines[l].calls += 1;
+                active_lines[l] += 1;
+            }
+            let node = node_of(Some(node), callee, &mut counts);
+            open.push(Open { function: callee, entry: vm.steps, line, node });
+        } else if vm.frames.len() < depth {
+            let o = open.pop().expect("no frame");
+            close(o, vm.steps, &mut functions, &mut active_functions, &mut lines, &mut active_lines);
+        }
+    }
+    while let Some(o) = open.pop() {
+        close(o, vm.steps, &mut functions, &mut active_functions, &mut lines, &mut active_lines);
+    }
+
+    let stacks = (0..counts.len())
+        .filter(|n| counts[*n] > 0)
+        .map(|n| {
+            let mut stack = vec![];
+            let mut node = Some(n);
+            while let Some(i) = node {
+                stack.push(nodes[i].1);
+                node = nodes[i].0;
+            }
+            stack.reverse();
+            (stack, counts[n])
+        })
+        .collect();
+    Profile {
+        total: vm.steps - start,
+        halted: vm.halted,
+        error,
+        functions,
+        lines: line_keys.iter().zip(lines).map(|((m, line), cost)| (map.maps[*m].file.clone(), *line, cost)).collect(),
+        stacks,
+    }
+}
+
+// Ends a call at `steps` instructions, adding its cost to its function and call site unless they are still active.
+fn close(o: Open, steps: u64, functions: &mut [Cost], active_functions: &mut [u32], lines: &mut [Cost], active_lines: &mut [u32]) {
+    let cost = steps - o.entry;
+    active_functions[o.function] -= 1;
+    if active_functions[o.function] == 0 {
+        functions[o.function].inclusive += cost;
+    }
+    if let Some(l) = o.line {
+        active_lines[l] -= 1;
+        if active_lines[l] == 0 {
+            lines[l].inclusive += cost;
+        }
+    }
+}
+
+impl Profile {
+    /// A table of the functions by instructions executed in their own code, then one of the lines of source
+    /// by instructions executed on them and in the calls they make.
+    pub fn report(&self, program: &Program) -> String {
+        let mut s = format!("{} instructions", self.total);
+        s += match (&self.error, self.halted) {
+            (Some(e), _) => format!(", stopped by an error: {}\n", e),
+            (None, true) => String::from(", the program halted\n"),
+            (None, false) => String::from(", the program was stopped\n"),
+        }.as_str();
+        let percent = |n: u64| format!("{:.1}%", n as f64 * 100.0 / self.total.max(1) as f64);
+
+        let mut functions: Vec<(usize, &Cost)> = self.functions.iter().enumerate().filter(|(_, c)| c.calls > 0).collect();
+        functions.sort_by_key(|(i, c)| (std::cmp::Reverse(c.exclusive), *i));
+        let mut rows = vec![["FUNCTION", "CALLS", "SELF", "SELF%", "TOTAL", "TOTAL%"].map(String::from)];
+        for (i, c) in functions {
+            rows.push([program.functions[i].name.clone(), c.calls.to_string(), c.exclusive.to_string(),
+                percent(c.exclusive), c.inclusive.to_string(), percent(c.inclusive)]);
+        }
+        s += "\n";
+        s += &table(&rows);
+
+        let mut lines: Vec<&(String, usize, Cost)> = self.lines.iter().filter(|(_, _, c)| c.exclusive > 0).collect();
+        lines.sort_by(|a, b| b.2.inclusive.cmp(&a.2.inclusive).then((&a.0, a.1).cmp(&(&b.0, b.1))));
+        let mut rows = vec![["LINE", "CALLS", "SELF", "SELF%", "TOTAL", "TOTAL%"].map(String::from)];
+        for (file, line, c) in lines {
+            rows.push([format!("{}:{}", file, line), c.calls.to_string(), c.exclusive.to_string(),
+                percent(c.exclusive), c.inclusive.to_string(), percent(c.inclusive)]);
+        }
+        if rows.len() > 1 {
+            s += "\n";
+            s += &table(&rows);
+        }
+        s
+    }
+
+    /// The call stacks with the instructions executed in each, one `Outer;Inner count` line per stack,
+    /// the folded format read by flame graph tools.
+    pub fn folded(&self, program: &Program) -> String {
+        let mut lines: Vec<String> = self.stacks.iter()
+            .map(|(stack, count)| {
+                let names: Vec<&str> = stack.iter().map(|f| program.functions[*f].name.as_str()).collect();
+                format!("{} {}\n", names.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+// Left-aligned first column, right-aligned numbers.
+fn table(rows: &[[String; 6]]) -> String {
+    let widths: Vec<usize> = (0..6).map(|c| rows.iter().map(|r| r[c].chars().count()).max().unwrap()).collect();
+    let mut s = String::new();
+    for row in rows.iter() {
+        let cells: Vec<String> = row.iter().zip(widths.iter()).enumerate()
+            .map(|(i, (cell, w))| if i == 0 { format!("{:w$}", cell, w = w) } else { format!("{:>w$}", cell, w = w) })
+            .collect();
+        s += &format!("  {}\n", cells.join("  "));
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_profile() {
+        let dir = env::temp_dir().join(format!("jackc_profile_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let src = "\
+class Main {
+    function void main() {
+        do Main.fact(4);
+        do Main.square(3);
+        return;
+    }
+
+    function int fact(int n) {
+        if (n < 2) { return 1; }
+        return n * Main.fact(n - 1);
+    }
+
+    function int square(int n) {
+        return n * n;
+    }
+}
+";
+        fs::write(dir.join("Main.jack"), src).unwrap();
+        for os in ["Sys", "Memory", "Math", "Screen", "Output", "Keyboard", "String", "Array"] {
+            fs::copy(format!("./tests/Seven/{}.vm", os), dir.join(format!("{}.vm", os))).unwrap();
+        }
+        let mut compiler = Compiler::default();
+        compiler.set_emit(vec![Emit::Map]);
+        assert!(compiler.run(&dir.join("Main.jack")));
+        let program = Program::load_dir(&dir).unwrap();
+        let map = ProgramMap::new(&program, SourceMap::load_dir(&dir).unwrap()).unwrap();
+        let mut vm = Vm::new(program).unwrap();
+        let profile = profile(&mut vm, &map, 10_000_000);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let program = &vm.program;
+        let cost = |name: &str| profile.functions[program.function_index(name).unwrap()];
+        assert!(profile.halted);
+        assert_eq!(profile.total, profile.functions.iter().map(|c| c.exclusive).sum::<u64>());
+        assert_eq!(cost("Sys.init").inclusive, profile.total);
+        assert_eq!(cost("Main.fact").calls, 4);
+        // recursive calls are counted once
+        let fact = cost("Main.fact");
+        let main = cost("Main.main");
+        assert_eq!(main.inclusive, main.exclusive + fact.inclusive + cost("Main.square").inclusive);
+        // line 10 makes the recursive calls and 3 multiplications, line 14 the last one
+        let line = |n: usize| profile.lines.iter().find(|(f, l, _)| f == "Main.jack" && *l == n).unwrap().2;
+        assert_eq!(line(10).calls, 6);
+        assert_eq!(line(14).calls, 1);
+        assert_eq!(line(3).inclusive, line(3).exclusive + fact.inclusive);
+        assert!(line(10).inclusive > fact.exclusive && line(10).inclusive < fact.inclusive);
+
+        let report = profile.report(program);
+        assert!(report.starts_with(&format!("{} instructions, the program halted\n\n  FUNCTION", profile.total)), "{}", report);
+        assert!(report.contains("\n  LINE "), "{}", report);
+        let folded = profile.folded(program);
+        assert!(folded.contains("\nSys.init;Main.main;Main.fact;Main.fact;Main.fact;Main.fact "), "{}", folded);
+        assert!(folded.contains("\nSys.init;Main.main;Main.fact;Main.fact;Main.fact;Math.multiply "), "{}", folded);
+        assert_eq!(folded.lines().map(|l| l.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap()).sum::<u64>(), profile.total);
+    }
+}
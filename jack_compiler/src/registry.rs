@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use crate::tokenizer::*;
+use crate::symbol::*;
+use crate::constant;
+use crate::declaration::*;
+use crate::diagnostic::*;
+use crate::symbol_table::*;
+use crate::options::*;
+
+/// Declarations of the standard OS classes, known to the compiler unless a project supplies their sources.
+pub const OS_DECLARATIONS: &str = include_str!("os.jackdecl");
+
+/// The classes of a whole program, known before any of them is compiled: the outlines of the sources
+/// and the declarations read from stub files, such as those of the OS classes.
+/// A class added later replaces an earlier one of the same name, so sources override stubs.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    classes: HashMap<String, ClassDecl>,
+    cycles: HashMap<(String, String), String>, // constants defined in terms of themselves, with the error
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry { classes: HashMap::new(), cycles: HashMap::new() }
+    }
+
+    /// A registry of the OS classes.
+    pub fn os() -> Self {
+        let mut registry = Registry::new();
+        registry.load_stubs(OS_DECLARATIONS, &Options::default()).expect("malformed OS declarations");
+        registry
+    }
+
+    pub fn add(&mut self, class: ClassDecl) {
+        self.classes.insert(class.name.clone(), class);
+    }
+
+    /// Adds the classes of a declaration file, in the format read by `ClassDecl::parse_all`.
+    pub fn load_stubs(&mut self, src: &str, options: &Options) -> Result<(), Diagnostic> {
+        let t = Tokenizer::with_options(src.as_bytes(), options);
+        if let Some(e) = t.errors.first() {
+            return Err(e.clone());
+        }
+        for class in ClassDecl::parse_all(&t, options)? {
+            self.add(class);
+        }
+        Ok(())
+    }
+
+    pub fn load_stub_file(&mut self, path: &Path, options: &Options) -> Result<(), Diagnostic> {
+        let file_name = path.to_string_lossy().into_owned();
+        let src = fs::read_to_string(path)
+            .map_err(|e| Diagnostic::error(None, format!("cannot open declaration file: {}", e)).in_file(&file_name))?;
+        self.load_stubs(&src, options).map_err(|d| d.in_file(&file_name))
+    }
+
+    pub fn class(&self, name: &str) -> Option<&ClassDecl> {
+        self.classes.get(name)
+    }
+
+    pub fn subroutine(&self, class: &str, name: &str) -> Option<&SubroutineDecl> {
+        self.class(class).and_then(|c| c.subroutine(name))
+    }
+
+    /// The value of the constant `NAME` of `class`, if it was evaluated.
+    pub fn constant(&self, class: &str, name: &str) -> Option<i16> {
+        self.class(class).and_then(|c| c.var(name)).filter(|v| v.kind == VarKind::Const).and_then(|v| v.value)
+    }
+
+    /// Why the constant `NAME` of `class` has no value, when it is defined in terms of itself.
+    pub fn constant_error(&self, class: &str, name: &str) -> Option<&str> {
+        self.cycles.get(&(class.to_string(), name.to_string())).map(|e| e.as_str())
+    }
+
+    /// Evaluates the constants that name constants of other classes, which their outline leaves unknown,
+    /// once every class is known. A constant is evaluated after those it names; the constants of a cycle
+    /// keep no value and get an error, see `constant_error`.
+    pub fn resolve_constants(&mut self, precedence: bool) {
+        let mut keys: Vec<(String, String)> = self.classes.values()
+            .flat_map(|c| c.vars.iter().filter(|v| v.kind == VarKind::Const).map(|v| (c.name.clone(), v.name.clone())))
+            .collect();
+        keys.sort();
+        let mut done = HashMap::new();
+        for key in keys {
+            self.resolve_constant(&key, precedence, &mut done, &mut vec![]);
+        }
+    }
+
+    // Depth-first: `done` maps the constants visited to whether they are finished, `path` leads to `key`.
+    fn resolve_constant(&mut self, key: &(String, String), precedence: bool,
+                        done: &mut HashMap<(String, String), bool>, path: &mut Vec<(String, String)>) {
+        match done.get(key) {
+            Some(true) => { return; },
+            Some(false) => {
+                let cycle = &path[path.iter().position(|k| k == key).unwrap()..];
+                for (i, k) in cycle.iter().enumerate() {
+                    let names: Vec<String> = cycle[i..].iter().chain(cycle[..=i].iter()).map(|(c, n)| format!("{}.{}", c, n)).collect();
+                    self.cycles.insert(k.clone(), format!("constant {}.{} is defined in terms of itself: {}", k.0, k.1, names.join(" -> ")));
+                }
+                return;
+            },
+            None => (),
+        }
+        let (position, tokens) = match self.class(&key.0).map(|c| (c, c.vars.iter().position(|v| v.name == key.1))) {
+            Some((c, Some(i))) if c.vars[i].kind == VarKind::Const && c.vars[i].value.is_none() => (i, c.vars[i].init.clone()),
+            _ => { return; }
+        };
+        done.insert(key.clone(), false);
+        path.push(key.clone());
+        for dep in dependencies(&key.0, &tokens) {
+            // a constant of the same class is only visible after its declaration, as when the class is compiled
+            let earlier = self.class(&dep.0).and_then(|c| c.vars.iter().position(|v| v.name == dep.1)).is_some_and(|i| i < position);
+            if dep.0 != key.0 || earlier {
+                self.resolve_constant(&dep, precedence, done, path);
+            }
+        }
+        path.pop();
+        done.insert(key.clone(), true);
+        if self.cycles.contains_key(key) {
+            return;
+        }
+        let class = self.class(&key.0).unwrap();
+        let lookup = |name: &str| {
+            let (c, n) = name.split_once('.').unwrap_or((&key.0, name));
+            if c != key.0 {
+                return self.constant(c, n);
+            }
+            class.vars[..position].iter().find(|v| v.kind == VarKind::Const && v.name == n).and_then(|v| v.value)
+        };
+        let value = constant::evaluate(&tokens, precedence, &lookup).ok();
+        let class = self.classes.get_mut(&key.0).unwrap();
+        class.vars[position].value = value;
+    }
+}
+
+// The constants named by a constant expression of `class`, as (class, name).
+fn dependencies(class: &str, tokens: &[Token]) -> Vec<(String, String)> {
+    let mut deps = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        if let Token::Identifier(name) = &tokens[i] {
+            match (tokens.get(i + 1), tokens.get(i + 2)) {
+                (Some(Token::Symbol(Symbol::Dot)), Some(Token::Identifier(member))) => {
+                    deps.push((name.clone(), member.clone()));
+                    i += 2;
+                },
+                _ => deps.push((class.to_string(), name.clone())),
+            }
+        }
+        i += 1;
+    }
+    deps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyword::*;
+
+    #[test]
+    fn test_os_declarations() {
+        let os = Registry::os();
+        for class in ["Math", "String", "Array", "Output", "Screen", "Keyboard", "Memory", "Sys"] {
+            assert!(os.class(class).is_some(), "{}", class);
+        }
+        assert_eq!(os.subroutine("String", "new").unwrap().kind, Keyword::Constructor);
+        assert_eq!(os.subroutine("String", "appendChar").unwrap().signature(), "method String appendChar(char c)");
+        assert_eq!(os.subroutine("Screen", "drawLine").unwrap().params.len(), 4);
+    }
+
+    #[test]
+    fn test_sources_override_stubs() {
+        let mut registry = Registry::os();
+        let t = Tokenizer::new("class Math { function int abs(int x, int y) { return x; } }".as_bytes());
+        registry.add(ClassDecl::parse(&t).unwrap());
+        assert_eq!(registry.subroutine("Math", "abs").unwrap().params.len(), 2);
+        assert!(registry.subroutine("Math", "sqrt").is_none());
+        assert!(registry.load_stubs("class Foo { method void f() }", &Options::default()).is_err());
+    }
+
+    #[test]
+    fn test_resolve_constants() {
+        let ext = Options { extensions: true, ..Options::default() };
+        let mut registry = Registry::new();
+        registry.load_stubs("class A { const int X = B.Y + 1; const int Z = X * 2; }\n\
+                             class B { const int Y = C.W * 10; const int V = A.Z; }\n\
+                             class C { const int W = 4; }\n\
+                             class D { const int P = E.Q; const int R = 1; }\n\
+                             class E { const int Q = D.P + D.R; }", &ext).unwrap();
+        assert_eq!(registry.constant("A", "X"), None);
+        registry.resolve_constants(false);
+        assert_eq!(registry.constant("A", "X"), Some(41));
+        assert_eq!(registry.constant("A", "Z"), Some(82));
+        assert_eq!(registry.constant("B", "V"), Some(82));
+        assert_eq!(registry.constant_error("A", "X"), None);
+        assert_eq!(registry.constant("D", "P"), None);
+        assert_eq!(registry.constant("D", "R"), Some(1));
+        assert_eq!(registry.constant_error("D", "P"), Some("constant D.P is defined in terms of itself: D.P -> E.Q -> D.P"));
+        assert_eq!(registry.constant_error("E", "Q"), Some("constant E.Q is defined in terms of itself: E.Q -> D.P -> E.Q"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::fs;
+use std::path::Path;
+use crate::engine::*;
+use crate::json::*;
+use crate::symbol_table::*;
+use crate::tokenizer::*;
+use crate::vm::*;
+
+/// A range of a Jack source: 1-based line and column of its first byte, and line and column just after its last one.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+
+impl Location {
+    // tokens never span lines
+    fn of_token(span: &Span) -> Self {
+        Location { line: span.line, col: span.col, end_line: span.line, end_col: span.col + (span.end - span.start) }
+    }
+}
+
+/// A variable a debugger can show: a static or field of the class, or an argument or local of a function.
+/// Arguments of methods are numbered from 1, argument 0 being `this`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub kind: VarKind,
+    pub var_type: String,
+    pub index: usize,
+}
+
+impl Variable {
+    fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("name", self.name.as_str().into()),
+            ("kind", self.kind.to_string().into()),
+            ("type", self.var_type.as_str().into()),
+            ("index", self.index.into()),
+        ])
+    }
+
+    fn from_json(json: &Json) -> Result<Self, String> {
+        let field = |key| json.get(key).and_then(|v| v.as_str()).ok_or_else(|| format!("variable without a {}: {}", key, json));
+        let kind = match field("kind")? {
+            "static" => VarKind::Static,
+            "field"  => VarKind::Field,
+            "arg"    => VarKind::Arg,
+            "var"    => VarKind::Var,
+            k => { return Err(format!("unknown variable kind {}", k)); }
+        };
+        let index = json.get("index").and_then(|i| i.as_usize()).ok_or_else(|| format!("variable without an index: {}", json))?;
+        Ok(Variable { name: field("name")?.to_string(), kind, var_type: field("type")?.to_string(), index })
+    }
+}
+
+// The variables declared in `refs`, constants left out since they have no storage.
+fn variables(refs: &[SymbolRef]) -> Vec<Variable> {
+    refs.iter()
+        .filter(|r| r.span == r.decl && r.kind != VarKind::Const)
+        .map(|r| Variable { name: r.name.clone(), kind: r.kind, var_type: r.var_type.to_string(), index: r.index })
+        .collect()
+}
+
+/// The locations of the commands of one VM function, indexed like the commands of the function in the .vm file:
+/// 0 for the `function` command, then one per command of the body, labels included and comments excluded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionMap {
+    pub name: String, // Class.name
+    pub locations: Vec<Location>,
+    pub variables: Vec<Variable>, // arguments and locals, those of blocks included
+}
+
+/// Maps the VM code of a class back to its Jack source, written as the `.vm.map` file next to the `.vm` one:
+///
+/// `{"version":1,"file":"Main.jack","variables":[...],"functions":[{"name":"Main.main","instructions":[[line,col,end_line,end_col],...],"variables":[...]}]}`
+///
+/// Each command is located at the token read last when it was written, e.g. the operand of a `push`,
+/// the second operand of an operator or the closing parenthesis of a call.
+/// Variables are `{"name":"x","kind":"var","type":"int","index":0}`, with the kinds of `VarKind`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceMap {
+    pub file: String,
+    pub variables: Vec<Variable>, // statics and fields
+    pub functions: Vec<FunctionMap>,
+}
+
+impl SourceMap {
+    /// The map of the code written so far by `engine`, compiling `file`.
+    pub fn from_engine(file: &str, engine: &Engine) -> Self {
+        let refs = engine.symbol_refs();
+        let subroutines = engine.subroutines();
+        let class_end = subroutines.first().map_or(refs.len(), |s| s.refs_start);
+        let functions = engine.vm_locations().iter()
+            .map(|(name, spans)| {
+                let i = subroutines.iter().position(|s| &s.name == name);
+                let start = i.map_or(refs.len(), |i| subroutines[i].refs_start);
+                let end = i.and_then(|i| subroutines.get(i + 1)).map_or(refs.len(), |next| next.refs_start);
+                FunctionMap {
+                    name: name.clone(),
+                    locations: spans.iter().map(Location::of_token).collect(),
+                    variables: variables(&refs[start..end]),
+                }
+            })
+            .collect();
+        SourceMap { file: file.to_string(), variables: variables(&refs[..class_end]), functions }
+    }
+
+    pub fn function(&self, name: &str) -> Option<&FunctionMap> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    pub fn to_json(&self) -> Json {
+        let functions = self.functions.iter().map(|f| {
+            let instructions = f.locations.iter()
+                .map(|l| Json::Array(vec![l.line.into(), l.col.into(), l.end_line.into(), l.end_col.into()]))
+                .collect();
+            Json::object(vec![
+                ("name", f.name.as_str().into()),
+                ("instructions", Json::Array(instructions)),
+                ("variables", Json::Array(f.variables.iter().map(Variable::to_json).collect())),
+            ])
+        }).collect();
+        Json::object(vec![
+            ("version", Json::Number(1.0)),
+            ("file", self.file.as_str().into()),
+            ("variables", Json::Array(self.variables.iter().map(Variable::to_json).collect())),
+            ("functions", Json::Array(functions)),
+        ])
+    }
+
+    pub fn from_json(json: &Json) -> Result<Self, String> {
+        if json.get("version").and_then(|v| v.as_usize()) != Some(1) {
+            return Err(String::from("unsupported source map version"));
+        }
+        let file = json.get("file").and_then(|f| f.as_str()).ok_or("source map without a file")?;
+        let variables = |json: &Json| -> Result<Vec<Variable>, String> {
+            json.get("variables").and_then(|v| v.as_array()).into_iter().flatten().map(Variable::from_json).collect()
+        };
+        let mut functions = vec![];
+        for f in json.get("functions").and_then(|f| f.as_array()).ok_or("source map without functions")? {
+            let name = f.get("name").and_then(|n| n.as_str()).ok_or("function without a name")?;
+            let mut locations = vec![];
+            for i in f.get("instructions").and_then(|i| i.as_array()).ok_or("function without instructions")? {
+                let n: Vec<usize> = i.as_array().into_iter().flatten().filter_map(|n| n.as_usize()).collect();
+                match n[..] {
+                    [line, col, end_line, end_col] => locations.push(Location { line, col, end_line, end_col }),
+                    _ => { return Err(format!("bad instruction location in {}: {}", name, i)); }
+                }
+            }
+            functions.push(FunctionMap { name: name.to_string(), locations, variables: variables(f)? });
+        }
+        Ok(SourceMap { file: file.to_string(), variables: variables(json)?, functions })
+    }
+}
+
+impl SourceMap {
+    /// Reads the .vm.map files of `dir`, in file name order.
+    pub fn load_dir(dir: &Path) -> Result<Vec<SourceMap>, String> {
+        let mut paths: Vec<_> = dir.read_dir().map_err(|e| format!("cannot read {}: {}", dir.display(), e))?
+            .flatten()
+            .map(|f| f.path())
+            .filter(|p| p.to_string_lossy().ends_with(".vm.map"))
+            .collect();
+        paths.sort();
+        paths.iter()
+            .map(|p| fs::read_to_string(p).map_err(|e| e.to_string())
+                .and_then(|s| Json::parse(&s))
+                .and_then(|json| SourceMap::from_json(&json))
+                .map_err(|e| format!("{}: {}", p.display(), e)))
+            .collect()
+    }
+}
+
+/// The source maps of the classes of a program, linked to its functions.
+pub struct ProgramMap {
+    pub maps: Vec<SourceMap>,
+    functions: Vec<Option<(usize, usize)>>, // for each function of the program: index in `maps` and in its functions
+}
+
+impl ProgramMap {
+    /// Fails when a map does not match the code of a function, as when a class is recompiled without its map.
+    pub fn new(program: &Program, maps: Vec<SourceMap>) -> Result<ProgramMap, String> {
+        let mut functions = vec![None; program.functions.len()];
+        for (m, map) in maps.iter().enumerate() {
+            for (i, f) in map.functions.iter().enumerate() {
+                if let Some(index) = program.function_index(&f.name) {
+                    let function = &program.functions[index];
+                    if f.locations.len() != function.end - function.start {
+                        return Err(format!("the source map of {} does not match its VM code, recompile it", f.name));
+                    }
+                    functions[index] = Some((m, i));
+                }
+            }
+        }
+        Ok(ProgramMap { maps, functions })
+    }
+
+    /// The map of the function `f` of the program, with the index of its class in `maps`.
+    pub fn function(&self, f: usize) -> Option<(usize, &FunctionMap)> {
+        self.functions[f].map(|(m, i)| (m, &self.maps[m].functions[i]))
+    }
+
+    /// The location of the instruction `pc` of `program`, with the index of its class in `maps`.
+    pub fn location(&self, program: &Program, pc: usize) -> Option<(usize, Location)> {
+        let f = program.function_at(pc);
+        self.function(f).map(|(m, function)| (m, function.locations[pc - program.functions[f].start]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::*;
+
+    #[test]
+    fn test_source_map() {
+        let src = "class Main {\n    static int count;\n    function int twice(int x) {\n        return x\n            * 2;\n    }\n}\n";
+        let t = Tokenizer::with_options(src.as_bytes(), &Options::default());
+        let mut e = Engine::new(t, std::io::sink());
+        e.compile();
+        let map = SourceMap::from_engine("Main.jack", &e);
+        let at = |line, col, len| Location { line, col, end_line: line, end_col: col + len };
+        // function Main.twice 0, push argument 0, push constant 2, call Math.multiply 2, return
+        assert_eq!(map.functions, vec![FunctionMap {
+            name: String::from("Main.twice"),
+            locations: vec![at(3, 18, 5), at(4, 16, 1), at(5, 15, 1), at(5, 15, 1), at(5, 16, 1)],
+            variables: vec![Variable { name: String::from("x"), kind: VarKind::Arg, var_type: String::from("int"), index: 0 }],
+        }]);
+        let json = map.to_json().to_string();
+        assert!(json.starts_with("{\"version\":1,\"file\":\"Main.jack\",\"variables\":[{\"name\":\"count\",\"kind\":\"static\",\"type\":\"int\",\"index\":0}],\"functions\":[{\"name\":\"Main.twice\",\"instructions\":[[3,18,3,23],[4,16,4,17],"), "{}", json);
+        assert_eq!(SourceMap::from_json(&Json::parse(&json).unwrap()), Ok(map));
+    }
+}
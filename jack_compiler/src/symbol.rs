@@ -14,6 +14,17 @@ pub enum Symbol {
     Slash,
     And,
     Or,
+    AndAnd, // extension: short-circuit and
+    OrOr,   // extension: short-circuit or
+    // extension: compound assignments and increments
+    PlusAssign,
+    MinusAssign,
+    AsteriskAssign,
+    SlashAssign,
+    AndAssign,
+    OrAssign,
+    Increment,
+    Decrement,
     Not,
     LessThan,
     GreaterThan,
@@ -38,6 +49,16 @@ impl fmt::Display for Symbol {
             Symbol::Slash       => write!(f, "/"),
             Symbol::And         => write!(f, "&amp;"),
             Symbol::Or          => write!(f, "|"),
+            Symbol::AndAnd      => write!(f, "&amp;&amp;"),
+            Symbol::OrOr        => write!(f, "||"),
+            Symbol::PlusAssign     => write!(f, "+="),
+            Symbol::MinusAssign    => write!(f, "-="),
+            Symbol::AsteriskAssign => write!(f, "*="),
+            Symbol::SlashAssign    => write!(f, "/="),
+            Symbol::AndAssign      => write!(f, "&amp;="),
+            Symbol::OrAssign       => write!(f, "|="),
+            Symbol::Increment      => write!(f, "++"),
+            Symbol::Decrement      => write!(f, "--"),
             Symbol::Not         => write!(f, "~"),
             Symbol::LessThan    => write!(f, "&lt;"),
             Symbol::GreaterThan => write!(f, "&gt;"),
@@ -59,6 +80,37 @@ impl fmt::Display for UndefinedSymbol {
 }
 
 impl Symbol {
+    /// Binding strength of a binary operator with --precedence: '* /' over '+ -' over comparisons
+    /// over '& |' over '&&' over '||'. None for the other symbols.
+    pub fn precedence(&self) -> Option<u8> {
+        match self {
+            Symbol::Asterisk | Symbol::Slash                        => Some(5),
+            Symbol::Plus | Symbol::Minus                            => Some(4),
+            Symbol::LessThan | Symbol::GreaterThan | Symbol::Equal  => Some(3),
+            Symbol::And | Symbol::Or                                => Some(2),
+            Symbol::AndAnd                                          => Some(1),
+            Symbol::OrOr                                            => Some(0),
+            _ => None,
+        }
+    }
+
+    /// The extension symbol written as this symbol followed by `next`, such as `+=` or `&&`.
+    pub fn compound(&self, next: u8) -> Option<Symbol> {
+        match (self, next) {
+            (Symbol::And, b'&')      => Some(Symbol::AndAnd),
+            (Symbol::Or, b'|')       => Some(Symbol::OrOr),
+            (Symbol::Plus, b'=')     => Some(Symbol::PlusAssign),
+            (Symbol::Minus, b'=')    => Some(Symbol::MinusAssign),
+            (Symbol::Asterisk, b'=') => Some(Symbol::AsteriskAssign),
+            (Symbol::Slash, b'=')    => Some(Symbol::SlashAssign),
+            (Symbol::And, b'=')      => Some(Symbol::AndAssign),
+            (Symbol::Or, b'=')       => Some(Symbol::OrAssign),
+            (Symbol::Plus, b'+')     => Some(Symbol::Increment),
+            (Symbol::Minus, b'-')    => Some(Symbol::Decrement),
+            _ => None,
+        }
+    }
+
     pub fn from_u8(b: u8) -> Result<Self, UndefinedSymbol> {
         match b {
             b'{' => Ok(Symbol::BraceL),
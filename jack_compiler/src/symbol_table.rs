@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::fmt;
+use crate::tokenizer::Span;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum VarType {
@@ -14,18 +15,32 @@ impl fmt::Display for VarType {
         match self {
             VarType::Int       => write!(f, "int"),
             VarType::Char      => write!(f, "char"), 
-            VarType::Boolean   => write!(f, "bool"),
+            VarType::Boolean   => write!(f, "boolean"),
             VarType::ClassName(class_name) => write!(f, "{}", &class_name)
         }
     }
 }
 
+/// A name declared twice in the same scope, with the place of the declaration that stays in effect.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateDeclaration {
+    pub name: String,
+    pub previous: Span,
+}
+
+impl fmt::Display for DuplicateDeclaration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is already declared in this scope", self.name)
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum VarKind {
     Static,
     Field,
     Arg,
     Var,
+    Const, // extension: class-level named constant, substituted at every use
 }
 
 impl fmt::Display for VarKind {
@@ -34,7 +49,8 @@ impl fmt::Display for VarKind {
             VarKind::Static => write!(f, "static"),
             VarKind::Field  => write!(f, "field"), 
             VarKind::Arg    => write!(f, "arg"),
-            VarKind::Var    => write!(f, "var")
+            VarKind::Var    => write!(f, "var"),
+            VarKind::Const  => write!(f, "const"),
         }
     }
 }
@@ -43,23 +59,41 @@ pub struct VarInfo {
     var_type: VarType,
     kind: VarKind,
     index: usize,
+    span: Span,
+    value: Option<i16>, // for constants
 }
 
 impl VarInfo {
-    fn new(var_type: VarType, kind: VarKind, index: usize) -> Self {
+    fn new(var_type: VarType, kind: VarKind, index: usize, span: Span) -> Self {
         VarInfo {
             var_type,
             kind,
-            index
+            index,
+            span,
+            value: None,
         }
     }
 }
 
+/// One occurrence of a variable name in the source, resolved against the symbol table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SymbolRef {
+    pub name: String,
+    pub span: Span,
+    pub decl: Span,
+    pub kind: VarKind,
+    pub var_type: VarType,
+    pub index: usize,
+    pub write: bool, // the variable itself is assigned here
+}
+
+#[derive(Default)]
 struct VarCounter {
     count_static: usize,
     count_field: usize,
     count_arg: usize,
     count_var: usize,
+    count_const: usize,
 }
 
 impl VarCounter {
@@ -68,7 +102,8 @@ impl VarCounter {
             count_static: 0,
             count_field : 0,
             count_arg   : 0,
-            count_var   : 0
+            count_var   : 0,
+            count_const : 0,
         }
     }
 
@@ -85,6 +120,9 @@ impl VarCounter {
             },
             VarKind::Var => {
                 self.count_var += 1;
+            },
+            VarKind::Const => {
+                self.count_const += 1;
             }
         }
     }
@@ -102,6 +140,9 @@ impl VarCounter {
             },
             VarKind::Var => {
                 self.count_var
+            },
+            VarKind::Const => {
+                self.count_const
             }
         }
     }
@@ -111,14 +152,25 @@ impl VarCounter {
         self.count_field = 0;
         self.count_arg = 0;
         self.count_var = 0;
+        self.count_const = 0;
     }
 }
 
+// A block opened inside a subroutine: the entries its declarations hid and the local count on entry.
+#[derive(Default)]
+struct Scope {
+    shadowed: Vec<(String, Option<VarInfo>)>,
+    count_var: usize,
+}
+
+#[derive(Default)]
 pub struct SymbolTable {
     tbl_cls: HashMap<String, VarInfo>,
     tbl_sub: HashMap<String, VarInfo>,
     cnt_cls: VarCounter,
     cnt_sub: VarCounter,
+    scopes: Vec<Scope>, // blocks nested in the subroutine body, innermost last
+    max_var: usize,     // local slots the subroutine needs
 }
 
 impl SymbolTable {
@@ -128,25 +180,95 @@ impl SymbolTable {
             tbl_sub: HashMap::<String, VarInfo>::new(),
             cnt_cls: VarCounter::new(),
             cnt_sub: VarCounter::new(),
+            scopes: vec![],
+            max_var: 0,
         }
     }
 
     pub fn start_subroutine(&mut self) {
         self.tbl_sub.clear();
         self.cnt_sub.clear();
+        self.scopes.clear();
+        self.max_var = 0;
     }
 
-    pub fn define(&mut self, name: &str, var_kind: VarKind, var_type: VarType,) {
+    /// Opens a block: the locals declared until the matching `end_scope` are visible only inside it.
+    pub fn begin_scope(&mut self) {
+        self.scopes.push(Scope { shadowed: vec![], count_var: self.cnt_sub.count_var });
+    }
+
+    /// Closes the innermost block, restoring the variables its declarations hid.
+    /// Its local slots are free again for the blocks that follow.
+    pub fn end_scope(&mut self) {
+        let scope = self.scopes.pop().expect("end_scope without begin_scope");
+        for (name, info) in scope.shadowed.into_iter().rev() {
+            match info {
+                Some(info) => { self.tbl_sub.insert(name, info); },
+                None => { self.tbl_sub.remove(&name); }
+            }
+        }
+        self.cnt_sub.count_var = scope.count_var;
+    }
+
+    /// Whether declarations go to a block rather than the subroutine scope.
+    pub fn in_block(&self) -> bool {
+        !self.scopes.is_empty()
+    }
+
+    /// The number of local slots the current subroutine needs: the most locals in scope at any point.
+    pub fn local_count(&self) -> usize {
+        self.max_var
+    }
+
+    /// Declares a variable, unless its name is already declared in the same scope: statics, fields and constants
+    /// share the class scope, arguments and the locals at the top of the body the subroutine scope, and each block
+    /// has its own. The first declaration stays in effect after an error.
+    pub fn define(&mut self, name: &str, var_kind: VarKind, var_type: VarType,) -> Result<(), DuplicateDeclaration> {
+        self.define_at(name, var_kind, var_type, Span::default())
+    }
+
+    /// Same as `define`, remembering where the variable was declared.
+    pub fn define_at(&mut self, name: &str, var_kind: VarKind, var_type: VarType, span: Span) -> Result<(), DuplicateDeclaration> {
+        let previous = match var_kind {
+            VarKind::Static | VarKind::Field | VarKind::Const => self.tbl_cls.get(name),
+            VarKind::Arg | VarKind::Var => match self.scopes.last() {
+                Some(scope) if !scope.shadowed.iter().any(|(n, _)| n == name) => None,
+                _ => self.tbl_sub.get(name),
+            },
+        };
+        if let Some(info) = previous {
+            return Err(DuplicateDeclaration { name: name.to_string(), previous: info.span });
+        }
         match var_kind {
-            VarKind::Static | VarKind::Field => {
-                self.tbl_cls.insert(name.into(), VarInfo::new(var_type, var_kind, self.cnt_cls.get_count(var_kind)));
+            VarKind::Static | VarKind::Field | VarKind::Const => {
+                self.tbl_cls.insert(name.into(), VarInfo::new(var_type, var_kind, self.cnt_cls.get_count(var_kind), span));
                 self.cnt_cls.count_up(var_kind);
             },
             VarKind::Arg | VarKind::Var => {
-                self.tbl_sub.insert(name.into(), VarInfo::new(var_type, var_kind, self.cnt_sub.get_count(var_kind)));
+                let prev = self.tbl_sub.insert(name.into(), VarInfo::new(var_type, var_kind, self.cnt_sub.get_count(var_kind), span));
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.shadowed.push((name.into(), prev));
+                }
                 self.cnt_sub.count_up(var_kind);
+                self.max_var = self.max_var.max(self.cnt_sub.count_var);
             }
         }
+        Ok(())
+    }
+
+    /// Sets the value of the constant `name`, once its expression has been evaluated.
+    pub fn set_value(&mut self, name: &str, value: i16) {
+        if let Some(info) = self.tbl_cls.get_mut(name) {
+            info.value = Some(value);
+        }
+    }
+
+    /// The value of `name` if it is a constant in scope whose value is known.
+    pub fn value_of(&self, name: &str) -> Option<i16> {
+        match self.tbl_sub.get(name) {
+            Some(_) => None,
+            None => self.tbl_cls.get(name).and_then(|i| i.value),
+        }
     }
 
     pub fn contains(&mut self, key: &str) -> bool {
@@ -155,7 +277,7 @@ impl SymbolTable {
 
     pub fn var_count(&mut self, var_kind: VarKind) -> usize {
         match var_kind {
-            VarKind::Static | VarKind::Field => {
+            VarKind::Static | VarKind::Field | VarKind::Const => {
                 self.cnt_cls.get_count(var_kind)
             },
             VarKind::Arg | VarKind::Var => {
@@ -165,12 +287,12 @@ impl SymbolTable {
     }
 
     pub fn kind_of(&self, name: &str) -> Option<&VarKind> {
-        match self.tbl_sub.get(name.into()) {
+        match self.tbl_sub.get(name) {
             Some(i) => {
                 Some(&i.kind)
             },
             None => {
-                match self.tbl_cls.get(name.into()) {
+                match self.tbl_cls.get(name) {
                     Some(j) => {
                         Some(&j.kind)
                     },
@@ -183,12 +305,12 @@ impl SymbolTable {
     }
 
     pub fn type_of(&self, name: &str) -> Option<&VarType> {
-        match self.tbl_sub.get(name.into()) {
+        match self.tbl_sub.get(name) {
             Some(i) => {
                 Some(&i.var_type)
             },
             None => {
-                match self.tbl_cls.get(name.into()) {
+                match self.tbl_cls.get(name) {
                     Some(j) => {
                         Some(&j.var_type)
                     },
@@ -200,13 +322,31 @@ impl SymbolTable {
         }
     }
 
+    pub fn span_of(&self, name: &str) -> Option<&Span> {
+        match self.tbl_sub.get(name) {
+            Some(i) => {
+                Some(&i.span)
+            },
+            None => {
+                match self.tbl_cls.get(name) {
+                    Some(j) => {
+                        Some(&j.span)
+                    },
+                    None => {
+                        None
+                    }
+                }
+            }
+        }
+    }
+
     pub fn index_of(&self, name: &str) -> Option<&usize> {
-        match self.tbl_sub.get(name.into()) {
+        match self.tbl_sub.get(name) {
             Some(i) => {
                 Some(&i.index)
             },
             None => {
-                match self.tbl_cls.get(name.into()) {
+                match self.tbl_cls.get(name) {
                     Some(j) => {
                         Some(&j.index)
                     },
@@ -237,11 +377,11 @@ mod tests {
     #[test]
     fn test_define_symbols() {
         let mut test = SymbolTable::new();
-        test.define("test1", VarKind::Var, VarType::Boolean);
-        test.define("test2", VarKind::Arg, VarType::Int);
-        test.define("test3", VarKind::Static, VarType::Char);
-        test.define("test4", VarKind::Field, VarType::Int);
-        test.define("test5", VarKind::Static, VarType::ClassName(String::from("TestClass")));
+        test.define("test1", VarKind::Var, VarType::Boolean).unwrap();
+        test.define("test2", VarKind::Arg, VarType::Int).unwrap();
+        test.define("test3", VarKind::Static, VarType::Char).unwrap();
+        test.define("test4", VarKind::Field, VarType::Int).unwrap();
+        test.define("test5", VarKind::Static, VarType::ClassName(String::from("TestClass"))).unwrap();
         assert_eq!(test.kind_of("test1"), Some(&VarKind::Var));
         assert_eq!(test.type_of("test2"), Some(&VarType::Int));
         assert_eq!(test.index_of("test5"), Some(&1));
@@ -252,11 +392,11 @@ mod tests {
     #[test]
     fn test_start_subroutine() {
         let mut test = SymbolTable::new();
-        test.define("test1", VarKind::Var, VarType::Boolean);
-        test.define("test2", VarKind::Arg, VarType::Int);
-        test.define("test3", VarKind::Static, VarType::Char);
-        test.define("test4", VarKind::Field, VarType::Int);
-        test.define("test5", VarKind::Static, VarType::ClassName(String::from("TestClass")));
+        test.define("test1", VarKind::Var, VarType::Boolean).unwrap();
+        test.define("test2", VarKind::Arg, VarType::Int).unwrap();
+        test.define("test3", VarKind::Static, VarType::Char).unwrap();
+        test.define("test4", VarKind::Field, VarType::Int).unwrap();
+        test.define("test5", VarKind::Static, VarType::ClassName(String::from("TestClass"))).unwrap();
         test.start_subroutine();
         assert_eq!(test.kind_of("test1"), None);
         assert_eq!(test.type_of("test2"), None);
@@ -267,4 +407,45 @@ mod tests {
         assert_eq!(test.var_count(VarKind::Arg), 0);
         assert_eq!(test.kind_of("ghost"), None);
     }
+
+    #[test]
+    fn test_nested_scopes() {
+        let mut test = SymbolTable::new();
+        test.define("x", VarKind::Var, VarType::Int).unwrap();
+        test.begin_scope();
+        test.define("x", VarKind::Var, VarType::Boolean).unwrap();
+        test.define("y", VarKind::Var, VarType::Int).unwrap();
+        assert_eq!(test.type_of("x"), Some(&VarType::Boolean));
+        assert_eq!(test.index_of("y"), Some(&2));
+        test.end_scope();
+        assert_eq!(test.type_of("x"), Some(&VarType::Int));
+        assert_eq!(test.kind_of("y"), None);
+        // a sibling block reuses the slots of the previous one
+        test.begin_scope();
+        test.define("z", VarKind::Var, VarType::Char).unwrap();
+        assert_eq!(test.index_of("z"), Some(&1));
+        test.end_scope();
+        assert_eq!(test.var_count(VarKind::Var), 1);
+        assert_eq!(test.local_count(), 3);
+    }
+
+    #[test]
+    fn test_duplicate_declarations() {
+        let mut test = SymbolTable::new();
+        let at = |line| Span { line, ..Span::default() };
+        test.define_at("x", VarKind::Field, VarType::Int, at(1)).unwrap();
+        assert_eq!(test.define_at("x", VarKind::Static, VarType::Char, at(2)),
+            Err(DuplicateDeclaration { name: String::from("x"), previous: at(1) }));
+        assert_eq!(test.var_count(VarKind::Static), 0);
+        test.define_at("a", VarKind::Arg, VarType::Int, at(3)).unwrap();
+        assert!(test.define_at("a", VarKind::Var, VarType::Int, at(4)).is_err());
+        assert_eq!(test.var_count(VarKind::Var), 0);
+        // locals may hide fields and the variables of enclosing blocks
+        test.define_at("x", VarKind::Var, VarType::Int, at(5)).unwrap();
+        test.begin_scope();
+        test.define_at("a", VarKind::Var, VarType::Int, at(6)).unwrap();
+        assert_eq!(test.define_at("a", VarKind::Var, VarType::Int, at(7)).unwrap_err().previous, at(6));
+        test.end_scope();
+        assert_eq!(test.kind_of("a"), Some(&VarKind::Arg));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,99 @@
+use crate::engine::*;
+use crate::keyword::*;
+use crate::symbol_table::*;
+use crate::tokenizer::*;
+
+/// Lists the variables of a compiled class, then those of each subroutine: where each one is declared,
+/// the VM segment and index it lives in, and every place it is read or written.
+/// Locations are `line:col` in `file_name`.
+pub fn symbol_report(file_name: &str, engine: &Engine) -> String {
+    let refs = engine.symbol_refs();
+    let subroutines = engine.subroutines();
+    let class_end = subroutines.first().map_or(refs.len(), |s| s.refs_start);
+    let mut s = format!("class {} ({})\n", engine.class_name(), file_name);
+    s += &table(&refs[..class_end], refs);
+    for (i, sub) in subroutines.iter().enumerate() {
+        let end = subroutines.get(i + 1).map_or(refs.len(), |next| next.refs_start);
+        s += &format!("\n{} {}\n", sub.kind, sub.name);
+        if sub.kind == Keyword::Method {
+            s += "  (argument 0 is this)\n";
+        }
+        s += &table(&refs[sub.refs_start..end], &refs[sub.refs_start..end]);
+    }
+    s
+}
+
+// One row per declaration in `decls`, with its uses found in `uses`.
+fn table(decls: &[SymbolRef], uses: &[SymbolRef]) -> String {
+    let mut rows = vec![["NAME", "KIND", "TYPE", "SLOT", "DECLARED", "WRITTEN", "READ"].map(String::from)];
+    for d in decls.iter().filter(|d| d.span == d.decl) {
+        let places = |write: bool| {
+            let list: Vec<String> = uses.iter()
+                .filter(|r| r.decl == d.decl && r.span != d.decl && r.write == write)
+                .map(|r| location(r.span))
+                .collect();
+            if list.is_empty() { String::from("-") } else { list.join(", ") }
+        };
+        rows.push([d.name.clone(), d.kind.to_string(), d.var_type.to_string(), slot(d), location(d.decl), places(true), places(false)]);
+    }
+    if rows.len() == 1 {
+        return String::from("  (no variables)\n");
+    }
+    let widths: Vec<usize> = (0..7).map(|c| rows.iter().map(|r| r[c].chars().count()).max().unwrap()).collect();
+    let mut s = String::new();
+    for row in rows.iter() {
+        let cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, w)| format!("{:w$}", cell, w = w)).collect();
+        s += &format!("  {}\n", cells.join("  ").trim_end());
+    }
+    s
+}
+
+// The VM memory segment and index of a variable.
+fn slot(r: &SymbolRef) -> String {
+    match r.kind {
+        VarKind::Static => format!("static {}", r.index),
+        VarKind::Field  => format!("this {}", r.index),
+        VarKind::Arg    => format!("argument {}", r.index),
+        VarKind::Var    => format!("local {}", r.index),
+        VarKind::Const  => String::from("-"),
+    }
+}
+
+fn location(span: Span) -> String {
+    format!("{}:{}", span.line, span.col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::*;
+    use std::io;
+
+    #[test]
+    fn test_symbol_report() {
+        let src = "class Counter {
+    field int count;
+    method void add(int n) {
+        var int old;
+        let old = count;
+        let count = old + n;
+        let count++;
+        return;
+    }
+}
+";
+        let ext = Options { extensions: true, ..Options::default() };
+        let mut e = Engine::with_options(Tokenizer::with_options(src.as_bytes(), &ext), io::sink(), ext);
+        e.compile();
+        assert_eq!(symbol_report("Counter.jack", &e), "class Counter (Counter.jack)
+  NAME   KIND   TYPE  SLOT    DECLARED  WRITTEN     READ
+  count  field  int   this 0  2:15      6:13, 7:13  5:19, 7:13
+
+method Counter.add
+  (argument 0 is this)
+  NAME  KIND  TYPE  SLOT        DECLARED  WRITTEN  READ
+  n     arg   int   argument 1  3:25      -        6:27
+  old   var   int   local 0     4:17      5:13     6:21
+");
+    }
+}
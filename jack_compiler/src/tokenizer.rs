@@ -1,9 +1,10 @@
-use std::io::{Read, BufRead, BufReader};
-use std::io::ErrorKind;
-use std::fs::File;
+use std::borrow::Cow;
+use std::io::Read;
 use std::str::FromStr;
 use crate::keyword::*;
 use crate::symbol::*;
+use crate::diagnostic::*;
+use crate::options::*;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
@@ -11,144 +12,352 @@ pub enum Token {
     Keyword(Keyword),
     Symbol(Symbol),
     Identifier(String),
-    IntConst(i16),
+    IntConst(u16), // 0..=32768, the largest only valid right after a unary minus
     StringConst(String),
 }
 
+/// Location of a token in the source: byte range plus 1-based line and column of its first byte.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+// Byte cursor over the whole source which keeps track of the current line and column.
+struct Scanner<'a> {
+    src: &'a [u8],
+    pos: usize,
+    line: usize,
+    col: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(src: &'a [u8]) -> Self {
+        Scanner {
+            src,
+            pos: 0,
+            line: 1,
+            col: 1,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        if b == b'\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(b)
+    }
+
+    fn mark(&self) -> Span {
+        Span {
+            start: self.pos,
+            end: self.pos,
+            line: self.line,
+            col: self.col,
+        }
+    }
+
+    // Reads up to and including the closing `quote`, or up to the end of the line if there is none.
+    // Returns the bytes in between, with escape sequences kept as written, and whether the quote was found.
+    fn quoted(&mut self, quote: u8, escapes: bool) -> (Vec<u8>, bool) {
+        let mut bytes = vec![];
+        loop {
+            match self.peek() {
+                Some(b'\n') | None => { return (bytes, false); },
+                Some(c) if c == quote => {
+                    self.bump();
+                    return (bytes, true);
+                },
+                // a backslash escapes the next character, including the quote
+                Some(b'\\') if escapes => {
+                    bytes.push(b'\\');
+                    self.bump();
+                    if let Some(c) = self.peek().filter(|c| *c != b'\n') {
+                        bytes.push(c);
+                        self.bump();
+                    }
+                },
+                Some(c) => {
+                    bytes.push(c);
+                    self.bump();
+                },
+            }
+        }
+    }
+
+    fn span_from(&self, mark: Span) -> Span {
+        Span {
+            end: self.pos,
+            ..mark
+        }
+    }
+}
+
+/// A comment kept as trivia: the tokens never see it, but tools such as the formatter do.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    pub text: String, // including the comment delimiters
+    pub span: Span,
+}
+
+/// Whether `c` is in the Jack character set: printable ASCII, plus the Hack key codes 128 (newline)
+/// to 152 (F12) which the OS also accepts in strings.
+pub fn is_jack_char(c: char) -> bool {
+    matches!(c as u32, 32..=126 | 128..=152)
+}
+
+/// The largest integer constant the tokenizer accepts: Jack's range is 0..32767, but `-32768` is written
+/// as a unary minus applied to 32768, which the engine accepts in that position only.
+pub const INT_CONST_MAX: u16 = 32768;
+
 pub struct Tokenizer {
     pub tokens: Vec<Token>,
+    pub comments: Vec<Comment>,
+    pub errors: Vec<Diagnostic>, // lexical errors; the offending token is replaced so that tokenizing can go on
+    docs: Vec<(usize, String)>, // start of the token following a doc comment, comment text
+    spans: Vec<Span>,
+    last_span: Option<Span>,
+    source: Vec<u8>,
 }
 
 impl Tokenizer {
-    pub fn new(f: File) -> Self {
+    pub fn new<R: Read>(f: R) -> Self {
+        Tokenizer::with_options(f, &Options::default())
+    }
+
+    pub fn with_options<R: Read>(mut f: R, options: &Options) -> Self {
+        let mut src = vec![];
+        f.read_to_end(&mut src).expect("cannot read source");
         let mut tokens = vec![];
-        let mut reader = BufReader::new(f);
+        let mut spans = vec![];
+        let mut comments = vec![];
+        let mut errors = vec![];
+        let mut docs = vec![];
+        let mut pending_doc = None;
+        let mut sc = Scanner::new(&src);
         'tokenize: loop {
-            let mut ch = [0; 1];
-            match reader.read_exact(&mut ch) {
-                Ok(_) => {
-                    match ch[0] {
-                        // skip newline and ascii whitespace
-                        b'\n' => { continue 'tokenize; },
-                        c if c.is_ascii_whitespace() => { continue 'tokenize; },
-                        // If a number, it is an integerConstant. Read until the end of the number.
-                        b'0'..=b'9' => {
-                            let mut digits = vec![ch[0]];
-                            if let Ok(buf) = reader.fill_buf() {
-                                for d in buf.iter() {
-                                    match d {
-                                        b'0'..=b'9' => {
-                                            digits.push(*d);
-                                        },
-                                        _ => {
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
-                            reader.consume(digits.len()-1);
+            let mark = sc.mark();
+            let ch = match sc.bump() {
+                Some(c) => c,
+                None => { break 'tokenize; }, // reached EOF
+            };
+            match ch {
+                // skip newline and ascii whitespace
+                b'\n' => { continue 'tokenize; },
+                c if c.is_ascii_whitespace() => { continue 'tokenize; },
+                // If a number, it is an integerConstant. Read until the end of the number.
+                // With extensions it may also be hexadecimal (0x) or binary (0b) and contain '_' separators.
+                b'0'..=b'9' => {
+                    let radix = match (ch, sc.peek()) {
+                        (b'0', Some(b'x' | b'X')) if options.extensions => 16,
+                        (b'0', Some(b'b' | b'B')) if options.extensions => 2,
+                        _ => 10,
+                    };
+                    let mut digits = vec![];
+                    if radix == 10 {
+                        digits.push(ch);
+                    } else {
+                        sc.bump();
+                    }
+                    while let Some(d) = sc.peek() {
+                        match d {
+                            d if (d as char).is_digit(radix) => { digits.push(d); },
+                            b'_' if options.extensions => (),
+                            _ => { break; }
+                        }
+                        sc.bump();
+                    }
+                    let malformed = options.extensions && (
+                        digits.is_empty() || src[sc.pos - 1] == b'_' ||
+                        (radix != 10 && sc.peek().is_some_and(|c| c.is_ascii_alphanumeric())));
+                    if malformed {
+                        while let Some(b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'_') = sc.peek() {
+                            sc.bump();
+                        }
+                    }
 
-                            let int_const: i16 = digits
-                                .into_iter()
-                                .map(|d| (d - b'0') as i16)
-                                .fold(0, |acc, d| 10*acc + d);
-                            tokens.push(Token::IntConst(int_const));
-                        },
-                        // If a doublequote, it is beginning of a stringConstant. Read until the next doublequote appears.
-                        b'"' => {
-                            let mut string_const = vec![];
-                            reader.read_until(b'"', &mut string_const).expect("reached unexpected EOF while parsing StringConst");
-                            string_const.pop().unwrap(); // pop '"'
-                            tokens.push(Token::StringConst(String::from_utf8(string_const).unwrap()));
-                        },
-                        // If an alphabet or underscore, it is a keyword or identifier.
-                        b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
-                            let mut chars = vec![ch[0]];
-                            if let Ok(buf) = reader.fill_buf() {
-                                for c in buf.iter() {
-                                    match c {
-                                        b'0'..= b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
-                                            chars.push(*c);
-                                        },
-                                        _ => {
-                                            break;
-                                        }
-                                    }
-                                }
+                    let int_const = digits
+                        .into_iter()
+                        .map(|d| (d as char).to_digit(radix).unwrap())
+                        .fold(0u32, |acc, d| (radix*acc + d).min(INT_CONST_MAX as u32 + 1));
+                    let span = sc.span_from(mark);
+                    let lexeme = String::from_utf8_lossy(&src[span.start..span.end]);
+                    let int_const = if malformed {
+                        errors.push(Diagnostic::error(Some(span), format!("malformed integer constant {}", lexeme)));
+                        0
+                    } else if int_const > INT_CONST_MAX as u32 {
+                        errors.push(Diagnostic::error(Some(span), format!("integer constant {} is out of range (0..32767)", lexeme)));
+                        0
+                    } else {
+                        int_const as u16
+                    };
+                    tokens.push(Token::IntConst(int_const));
+                },
+                // If a doublequote, it is beginning of a stringConstant. Read until the next doublequote appears.
+                // A string constant may not contain a newline, so an unterminated one ends at the end of the line.
+                b'"' => {
+                    let (string_const, closed) = sc.quoted(b'"', options.extensions);
+                    if !closed {
+                        errors.push(Diagnostic::error(Some(sc.span_from(mark)), unclosed_message("string constant", sc.peek())));
+                    }
+                    let string_const = String::from_utf8_lossy(&string_const);
+                    tokens.push(Token::StringConst(string_chars(&string_const, mark, options, &mut errors)));
+                },
+                // With extensions, a character literal such as 'a' or '\n' is the integerConstant of its code.
+                b'\'' if options.extensions => {
+                    let (char_const, closed) = sc.quoted(b'\'', true);
+                    let span = sc.span_from(mark);
+                    if !closed {
+                        errors.push(Diagnostic::error(Some(span), unclosed_message("character literal", sc.peek())));
+                    }
+                    let chars = string_chars(&String::from_utf8_lossy(&char_const), mark, options, &mut errors);
+                    let mut it = chars.chars();
+                    let code = match (it.next(), it.next()) {
+                        (Some(c), None) => c as u16,
+                        _ => {
+                            if closed {
+                                errors.push(Diagnostic::error(Some(span), String::from("character literal must contain exactly one character")));
                             }
-                            reader.consume(chars.len()-1);
+                            0
+                        }
+                    };
+                    tokens.push(Token::IntConst(code));
+                },
+                // If an alphabet or underscore, it is a keyword or identifier.
+                b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                    let mut chars = vec![ch];
+                    while let Some(c @ (b'0'..= b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'_')) = sc.peek() {
+                        chars.push(c);
+                        sc.bump();
+                    }
 
-                            let word = std::str::from_utf8(&chars).unwrap();
-                            match Keyword::from_str(word) {
-                                Ok(kw) => {
-                                    tokens.push(Token::Keyword(kw));
-                                },
-                                Err(_) => {
-                                    tokens.push(Token::Identifier(word.to_string()));
-                                }
-                            }
+                    let word = std::str::from_utf8(&chars).unwrap();
+                    match Keyword::from_str(word) {
+                        Ok(kw) if !kw.is_extension() || options.extensions => {
+                            tokens.push(Token::Keyword(kw));
                         },
-                        // if a symbol, it is a symbol token or a comment.
-                        c => {
-                            match Symbol::from_u8(c) {
-                                Ok(sym) => {
-                                    match sym {
-                                        // If c is a /(slash), the next byte should be checked.
-                                        Symbol::Slash => {
-                                            if let Ok(buf) = reader.fill_buf() { // TODO: I need more efficient way to look ahead a buffer...
-                                                match buf[0] {
-                                                    // If / or *, it is followed by a comment, so skip it.
-                                                    b'/' => { // one line comment
-                                                        reader.consume(1);
-                                                        let mut comment = vec![];
-                                                        reader.read_until(b'\n', &mut comment).unwrap();
-                                                    },
-                                                    b'*' => {
-                                                        reader.consume(1);
-                                                        let mut comment = vec![];
-                                                        while let Ok(_) = reader.read_until(b'/', &mut comment) {
-                                                            if comment.len() >= 2 && comment[comment.len()-2] == b'*' {
-                                                                break;
-                                                            }
-                                                        }
-                                                    },
-                                                    _ => {
-                                                        // If not a comment, the slash is a symbol token.
-                                                        tokens.push(Token::Symbol(Symbol::from_u8(c).unwrap()));
-                                                    }
+                        _ => {
+                            tokens.push(Token::Identifier(word.to_string()));
+                        }
+                    }
+                },
+                // if a symbol, it is a symbol token or a comment.
+                c => {
+                    match Symbol::from_u8(c) {
+                        Ok(sym) => {
+                            match sym {
+                                // With extensions, some symbols pair with the next byte, as in && or +=.
+                                _ if options.extensions && sc.peek().and_then(|next| sym.compound(next)).is_some() => {
+                                    let next = sc.bump().unwrap();
+                                    tokens.push(Token::Symbol(sym.compound(next).unwrap()));
+                                },
+                                // If c is a /(slash), the next byte should be checked.
+                                Symbol::Slash => {
+                                    match sc.peek() {
+                                        // If / or *, it is followed by a comment, so keep it aside as trivia.
+                                        Some(b'/') => { // one line comment
+                                            while let Some(c) = sc.peek() {
+                                                if c == b'\n' {
+                                                    break;
                                                 }
+                                                sc.bump();
+                                            }
+                                            let span = sc.span_from(mark);
+                                            comments.push(Comment {
+                                                text: String::from_utf8_lossy(&src[span.start..span.end]).trim_end().to_string(),
+                                                span,
+                                            });
+                                            continue 'tokenize;
+                                        },
+                                        Some(b'*') => {
+                                            sc.bump();
+                                            let mut prev = 0;
+                                            let mut closed = false;
+                                            while let Some(c) = sc.bump() {
+                                                if prev == b'*' && c == b'/' {
+                                                    closed = true;
+                                                    break;
+                                                }
+                                                prev = c;
+                                            }
+                                            let span = sc.span_from(mark);
+                                            if !closed {
+                                                errors.push(Diagnostic::error(Some(Span { end: span.start + 2, ..span }),
+                                                    String::from("unterminated block comment")));
                                             }
+                                            let text = String::from_utf8_lossy(&src[span.start..span.end]).into_owned();
+                                            // A /** ... */ comment documents the token that follows it.
+                                            pending_doc = if text.starts_with("/**") && text.len() > 4 {
+                                                Some(doc_text(&text))
+                                            } else {
+                                                None
+                                            };
+                                            comments.push(Comment {
+                                                text,
+                                                span,
+                                            });
+                                            continue 'tokenize;
                                         },
-                                        // If the other symbol, it can immediately be added to tokens as a symbol.
                                         _ => {
-                                            tokens.push(Token::Symbol(Symbol::from_u8(c).unwrap()));
+                                            // If not a comment, the slash is a symbol token.
+                                            tokens.push(Token::Symbol(sym));
                                         }
                                     }
                                 },
-                                Err(e) => {
-                                    panic!("unexpected error occurred while tokenizing: {}", e);
+                                // If the other symbol, it can immediately be added to tokens as a symbol.
+                                _ => {
+                                    tokens.push(Token::Symbol(sym));
                                 }
                             }
+                        },
+                        Err(_) => {
+                            // A stray byte: report the whole character, even if it is not ASCII, and skip it.
+                            while let Some(0x80..=0xbf) = sc.peek() {
+                                sc.bump();
+                            }
+                            let span = sc.span_from(mark);
+                            let text = String::from_utf8_lossy(&src[span.start..span.end]);
+                            errors.push(Diagnostic::error(Some(span), format!("unexpected character '{}'", text)));
+                            continue 'tokenize;
                         }
                     }
-                },
-                Err(e) => {
-                    match e.kind() {
-                        ErrorKind::UnexpectedEof => { break 'tokenize; }, // reached EOF
-                        _ => panic!("unexpected error occurred while tokenizing: {}", e),
-                    }
                 }
             }
+            if let Some(doc) = pending_doc.take() {
+                docs.push((mark.start, doc));
+            }
+            spans.push(sc.span_from(mark));
         }
 
         let tokens = tokens.into_iter().rev().collect();
+        let spans = spans.into_iter().rev().collect();
 
         Tokenizer {
-            tokens: tokens,
+            tokens,
+            comments,
+            errors,
+            docs,
+            spans,
+            last_span: None,
+            source: src,
         }
     }
 
     pub fn get_next_token(&mut self) -> Token {
+        if let Some(span) = self.spans.pop() {
+            self.last_span = Some(span);
+        }
         self.tokens.pop().unwrap_or(Token::Empty())
     }
 
@@ -162,11 +371,227 @@ impl Tokenizer {
                       _ => None
         }
     }
+
+    /// The token `n` positions after the next one, `peek_nth_token(0)` being the next token.
+    pub fn peek_nth_token(&self, n: usize) -> Option<&Token> {
+        self.tokens.len().checked_sub(n + 1).map(|i| &self.tokens[i])
+    }
+
+    /// The remaining tokens with their spans, in source order.
+    pub fn source_tokens(&self) -> impl Iterator<Item = (&Token, &Span)> {
+        self.tokens.iter().rev().zip(self.spans.iter().rev())
+    }
+
+    /// The source text covered by `span`, e.g. a token exactly as it was written.
+    pub fn lexeme(&self, span: &Span) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.source[span.start..span.end])
+    }
+
+    /// The text of the 1-based `line` of the source, without its line break.
+    pub fn source_line(&self, line: usize) -> Cow<'_, str> {
+        let text = self.source.split(|b| *b == b'\n').nth(line.wrapping_sub(1)).unwrap_or(&[]);
+        String::from_utf8_lossy(text.strip_suffix(b"\r").unwrap_or(text))
+    }
+
+    /// The doc comment written right before the token at `span`, without its `/**`, `*/` and leading `*`s.
+    pub fn doc_comment(&self, span: &Span) -> Option<&str> {
+        self.docs.iter().find(|(start, _)| *start == span.start).map(|(_, doc)| doc.as_str())
+    }
+
+    /// Span of the most recently consumed token, or of the next one if nothing has been consumed yet.
+    pub fn current_span(&self) -> Option<Span> {
+        self.last_span.or_else(|| self.spans.last().copied())
+    }
+}
+
+fn unclosed_message(what: &str, next: Option<u8>) -> String {
+    match next {
+        Some(_) => format!("{} is not closed before the end of the line", what),
+        None    => format!("unterminated {}", what),
+    }
+}
+
+// The characters of a string constant or character literal with its escape sequences resolved. Each character written as is
+// is checked against the Jack character set; `body` starts one column after `mark`, the opening quote.
+fn string_chars(body: &str, mark: Span, options: &Options, errors: &mut Vec<Diagnostic>) -> String {
+    let mut string_const = String::new();
+    let mut chars = body.char_indices().enumerate();
+    while let Some((i, (at, c))) = chars.next() {
+        let mut span = Span { start: mark.start + 1 + at, end: mark.start + 1 + at + c.len_utf8(), line: mark.line, col: mark.col + 1 + i };
+        if c == '\\' && options.extensions {
+            // '\"' | '\'' | '\\' | '\n' | '\x' hexDigit hexDigit
+            let escape = chars.next().map(|(_, (_, e))| e);
+            let code = match escape {
+                Some('"')  => Some('"' as u32),
+                Some('\'') => Some('\'' as u32),
+                Some('\\') => Some('\\' as u32),
+                Some('n')  => Some(128), // newline key code of the Hack platform
+                Some('x')  => {
+                    let digits: String = [chars.next(), chars.next()].iter().flatten().map(|(_, (_, d))| *d).collect();
+                    span.end += 1 + digits.len();
+                    match u32::from_str_radix(&digits, 16) {
+                        Ok(code) if digits.len() == 2 => Some(code),
+                        _ => {
+                            errors.push(Diagnostic::error(Some(span), String::from("\\x escape needs two hexadecimal digits")));
+                            None
+                        }
+                    }
+                },
+                Some(e) => {
+                    span.end += e.len_utf8();
+                    errors.push(Diagnostic::error(Some(span), format!("unknown escape sequence '\\{}'", e.escape_debug())));
+                    None
+                },
+                None => {
+                    errors.push(Diagnostic::error(Some(span), String::from("unterminated escape sequence")));
+                    None
+                }
+            };
+            if let Some(code) = code {
+                string_const.push(char::from_u32(code).unwrap());
+            }
+            continue;
+        }
+        let message = if options.raw_chars && (c as u32) > 32767 {
+            Some(format!("character '{}' (U+{:04X}) does not fit in a Jack character code", c.escape_debug(), c as u32))
+        } else if !options.raw_chars && !is_jack_char(c) {
+            Some(format!("character '{}' (U+{:04X}) is not in the Jack character set", c.escape_debug(), c as u32))
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            errors.push(Diagnostic::error(Some(span), message));
+        }
+        string_const.push(c);
+    }
+    string_const
+}
+
+fn doc_text(comment: &str) -> String {
+    let body = comment.trim_start_matches("/**").trim_end_matches("*/");
+    let lines: Vec<&str> = body.lines()
+        .map(|l| {
+            let l = l.trim();
+            l.strip_prefix('*').map(|l| l.strip_prefix(' ').unwrap_or(l)).unwrap_or(l).trim_end()
+        })
+        .collect();
+    lines.join("\n").trim().to_string()
 }
 
 #[cfg(test)]
 mod tests {
     #[test]
+    fn test_int_const_range() {
+        use super::*;
+
+        for (src, value) in [("0", 0), ("00007", 7), ("32767", 32767), ("32768", 32768)] {
+            let t = Tokenizer::new(src.as_bytes());
+            assert!(t.errors.is_empty(), "{}", src);
+            assert_eq!(t.tokens, vec![Token::IntConst(value)]);
+        }
+        for src in ["32769", "40000", "65535", "65536", "99999999999999999999999"] {
+            let t = Tokenizer::new(format!("let x =\n  {};", src).as_bytes());
+            assert_eq!(t.errors.len(), 1, "{}", src);
+            let e = &t.errors[0];
+            assert_eq!(e.message, format!("integer constant {} is out of range (0..32767)", src));
+            assert_eq!(e.span.map(|s| (s.line, s.col, s.end - s.start)), Some((2, 3, src.len())));
+            // tokenizing goes on after the error
+            assert_eq!(t.tokens.len(), 5);
+        }
+    }
+
+    #[test]
+    fn test_lexical_errors() {
+        use super::*;
+
+        fn errors(src: &str) -> Vec<String> {
+            Tokenizer::new(src.as_bytes()).errors.iter()
+                .map(|e| { let s = e.span.unwrap(); format!("{}:{}: {}", s.line, s.col, e.message) })
+                .collect()
+        }
+        assert_eq!(errors("do f(\"abc);\nreturn;"), vec!["1:6: string constant is not closed before the end of the line"]);
+        assert_eq!(errors("let s = \"abc"), vec!["1:9: unterminated string constant"]);
+        assert_eq!(errors("let x = 1; /* comment\n * never closed"), vec!["1:12: unterminated block comment"]);
+        assert_eq!(errors("let x = 1;\n  let #y = @2;"), vec!["2:7: unexpected character '#'", "2:12: unexpected character '@'"]);
+        assert_eq!(errors("let caf\u{e9} = 1;"), vec!["1:8: unexpected character '\u{e9}'"]);
+        assert!(errors("/* a */ /** b **/ /*/ c */ let s = \"a // b\";").is_empty());
+
+        // the rest of the input is still tokenized
+        let t = Tokenizer::new("do f(\"abc);\nreturn;".as_bytes());
+        assert_eq!(t.tokens.into_iter().rev().collect::<Vec<Token>>(), vec![
+            Token::Keyword(Keyword::Do), Token::Identifier(String::from("f")), Token::Symbol(Symbol::ParenL),
+            Token::StringConst(String::from("abc);")), Token::Keyword(Keyword::Return), Token::Symbol(Symbol::SemiColon),
+        ]);
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        use super::*;
+
+        let ext = Options { extensions: true, ..Options::default() };
+        let lex = |src: &str, options: &Options| {
+            let t = Tokenizer::with_options(src.as_bytes(), options);
+            let errors: Vec<String> = t.errors.iter()
+                .map(|e| { let s = e.span.unwrap(); format!("{}:{}-{}: {}", s.line, s.col, s.col + s.end - s.start, e.message) })
+                .collect();
+            (t.tokens.into_iter().rev().collect::<Vec<Token>>(), errors)
+        };
+        let string = |s: &str| Token::StringConst(s.to_string());
+
+        assert_eq!(lex(r#""say \"hi\" \\ \n\x41\x7e""#, &ext), (vec![string("say \"hi\" \\ \u{80}A~")], vec![]));
+        // without extensions a backslash is an ordinary character and '"' ends the string
+        assert_eq!(lex(r#""a\" b"#, &Options::default()), (vec![string("a\\"), Token::Identifier(String::from("b"))], vec![]));
+        // an escaped code is not checked against the character set
+        assert_eq!(lex(r#""\x09""#, &ext), (vec![string("\t")], vec![]));
+        assert_eq!(lex(r#"  "a\qb\x4g\x""#, &ext).1, vec![
+            "1:5-7: unknown escape sequence '\\q'",
+            "1:8-12: \\x escape needs two hexadecimal digits",
+            "1:12-14: \\x escape needs two hexadecimal digits",
+        ]);
+        assert_eq!(lex("\"abc\\\ndo", &ext).1, vec![
+            "1:1-6: string constant is not closed before the end of the line",
+            "1:5-6: unterminated escape sequence",
+        ]);
+    }
+
+    #[test]
+    fn test_extended_literals() {
+        use super::*;
+
+        let ext = Options { extensions: true, ..Options::default() };
+        let lex = |src: &str, options: &Options| {
+            let t = Tokenizer::with_options(src.as_bytes(), options);
+            let errors: Vec<String> = t.errors.iter()
+                .map(|e| { let s = e.span.unwrap(); format!("{}-{}: {}", s.col, s.col + s.end - s.start, e.message) })
+                .collect();
+            (t.tokens.into_iter().rev().collect::<Vec<Token>>(), errors)
+        };
+        let ints = |v: &[u16]| v.iter().map(|i| Token::IntConst(*i)).collect::<Vec<Token>>();
+
+        assert_eq!(lex("0x4000 0XfF 0b1010 0B0 1_000 0x7f_ff 32_768", &ext), (ints(&[16384, 255, 10, 0, 1000, 32767, 32768]), vec![]));
+        assert_eq!(lex(r"'a' ' ' '\'' '\\' '\n' '\x1b' '~'", &ext), (ints(&[97, 32, 39, 92, 128, 27, 126]), vec![]));
+        assert_eq!(lex("0x 0x8001 0b102 0xag 1__0 12_", &ext).1, vec![
+            "1-3: malformed integer constant 0x",
+            "4-10: integer constant 0x8001 is out of range (0..32767)",
+            "11-16: malformed integer constant 0b102",
+            "17-21: malformed integer constant 0xag",
+            "27-30: malformed integer constant 12_",
+        ]);
+        assert_eq!(lex("'' 'ab' '\u{e9}' 'a", &ext).1, vec![
+            "1-3: character literal must contain exactly one character",
+            "4-8: character literal must contain exactly one character",
+            "10-12: character '\u{e9}' (U+00E9) is not in the Jack character set",
+            "14-16: unterminated character literal",
+        ]);
+        // plain Jack reads the same text as separate tokens
+        assert_eq!(lex("0x10 1_0", &Options::default()).0, vec![
+            Token::IntConst(0), Token::Identifier(String::from("x10")), Token::IntConst(1), Token::Identifier(String::from("_0")),
+        ]);
+        assert_eq!(lex("'a'", &Options::default()).1, vec!["1-2: unexpected character '''", "3-4: unexpected character '''"]);
+    }
+
+    #[test]
+    #[allow(unused_imports, clippy::manual_flatten)]
     fn test_export_xml() {
         use super::*;
         use std::path::Path;
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use crate::vm_writer::*;
+
+// Registers and regions of the RAM of the Hack platform, as laid out by the standard VM translator.
+pub const SP: usize = 0;
+pub const LCL: usize = 1;
+pub const ARG: usize = 2;
+pub const THIS: usize = 3;
+pub const THAT: usize = 4;
+const TEMP: usize = 5;
+const STATIC_START: usize = 16;
+const STACK_START: usize = 256;
+const STACK_END: usize = 2048;
+pub const RAM_SIZE: usize = 32768;
+
+/// A VM command with its label and function operands resolved.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Instruction {
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Arithmetic(Command),
+    Label,
+    Goto(usize),      // index in `Program::code`
+    IfGoto(usize),
+    Function(u16),    // number of locals
+    Call(usize, u16), // index in `Program::functions`, number of arguments
+    Return,
+}
+
+/// A VM function: the instructions `start..end` of `Program::code`, the `function` command first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub file: String, // the .vm file it comes from, without extension
+    pub start: usize,
+    pub end: usize,
+    pub static_base: usize, // address of static 0 of its file
+}
+
+/// The VM files of a program, loaded and linked.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub code: Vec<Instruction>,
+    pub functions: Vec<Function>,
+}
+
+// A jump or call whose target is known once every file is read.
+enum Target {
+    Label(usize, String), // function index, label name
+    Function(String),
+}
+
+impl Program {
+    /// Loads the program made of `files`, pairs of a file name without extension and its contents.
+    /// Static variables are allocated file by file from address 16, in the order of `files`.
+    /// Errors are `File.vm:line: message`.
+    pub fn parse(files: &[(String, String)]) -> Result<Program, String> {
+        let mut code = vec![];
+        let mut functions: Vec<Function> = vec![];
+        let mut labels = HashMap::new();
+        let mut targets = vec![];
+        let mut static_base = STATIC_START;
+        for (file, src) in files {
+            let first_function = functions.len();
+            let mut statics = 0;
+            for (n, line) in src.lines().enumerate() {
+                let err = |message: String| format!("{}.vm:{}: {}", file, n + 1, message);
+                let words: Vec<&str> = line.split("//").next().unwrap_or_default().split_whitespace().collect();
+                if words.is_empty() {
+                    continue;
+                }
+                let number = |i: usize| words.get(i).and_then(|w| w.parse::<u16>().ok()).ok_or_else(|| err(format!("number expected in {}", line.trim())));
+                let name = |i: usize| words.get(i).map(|w| w.to_string()).ok_or_else(|| err(format!("name expected in {}", line.trim())));
+                if words[0] != "function" && functions.len() == first_function {
+                    return Err(err(String::from("command outside a function")));
+                }
+                let function = functions.len().wrapping_sub(1);
+                let instruction = match words[0] {
+                    "push" | "pop" => {
+                        let segment = words.get(1).unwrap_or(&"").parse::<Segment>().map_err(err)?;
+                        let index = number(2)?;
+                        match (segment, index) {
+                            (Segment::Pointer, 2..) | (Segment::Temp, 8..) => { return Err(err(format!("{} {} is out of range", segment, index))); },
+                            (Segment::Const, _) if words[0] == "pop" => { return Err(err(String::from("cannot pop to constant"))); },
+                            (Segment::Static, i) => { statics = statics.max(i as usize + 1); },
+                            _ => (),
+                        }
+                        match words[0] {
+                            "push" => Instruction::Push(segment, index),
+                            _      => Instruction::Pop(segment, index),
+                        }
+                    },
+                    "label" => {
+                        if labels.insert((function, name(1)?), code.len()).is_some() {
+                            return Err(err(format!("label {} is defined twice", words[1])));
+                        }
+                        Instruction::Label
+                    },
+                    "goto" => {
+                        targets.push((code.len(), Target::Label(function, name(1)?), err(format!("no label {} in this function", words[1]))));
+                        Instruction::Goto(0)
+                    },
+                    "if-goto" => {
+                        targets.push((code.len(), Target::Label(function, name(1)?), err(format!("no label {} in this function", words[1]))));
+                        Instruction::IfGoto(0)
+                    },
+                    "call" => {
+                        targets.push((code.len(), Target::Function(name(1)?), err(format!("call to undefined function {}", words[1]))));
+                        Instruction::Call(0, number(2)?)
+                    },
+                    "function" => {
+                        if let Some(f) = functions.last_mut() {
+                            f.end = code.len();
+                        }
+                        functions.push(Function { name: name(1)?, file: file.clone(), start: code.len(), end: code.len(), static_base });
+                        Instruction::Function(number(2)?)
+                    },
+                    "return" => Instruction::Return,
+                    w => Instruction::Arithmetic(w.parse::<Command>().map_err(err)?),
+                };
+                code.push(instruction);
+            }
+            if let Some(f) = functions.last_mut() {
+                f.end = code.len();
+            }
+            static_base += statics;
+            if static_base > STACK_START {
+                return Err(format!("{}.vm: too many static variables", file));
+            }
+        }
+
+        let index: HashMap<&str, usize> = functions.iter().enumerate().map(|(i, f)| (f.name.as_str(), i)).collect();
+        for (at, target, message) in targets {
+            let resolved = match &target {
+                Target::Label(function, label) => labels.get(&(*function, label.clone())).copied(),
+                Target::Function(name) => index.get(name.as_str()).copied(),
+            };
+            let resolved = resolved.ok_or(message)?;
+            code[at] = match code[at] {
+                Instruction::Goto(_) => Instruction::Goto(resolved),
+                Instruction::IfGoto(_) => Instruction::IfGoto(resolved),
+                Instruction::Call(_, n_args) => Instruction::Call(resolved, n_args),
+                i => i,
+            };
+        }
+        Ok(Program { code, functions })
+    }
+
+    /// Loads the .vm files of `dir`, in file name order.
+    pub fn load_dir(dir: &Path) -> Result<Program, String> {
+        let mut paths: Vec<_> = dir.read_dir().map_err(|e| format!("cannot read {}: {}", dir.display(), e))?
+            .flatten()
+            .map(|f| f.path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "vm"))
+            .collect();
+        paths.sort();
+        let mut files = vec![];
+        for path in paths {
+            let src = fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+            files.push((path.file_stem().unwrap_or_default().to_string_lossy().into_owned(), src));
+        }
+        Program::parse(&files)
+    }
+
+    pub fn function_index(&self, name: &str) -> Option<usize> {
+        self.functions.iter().position(|f| f.name == name)
+    }
+
+    /// The function the instruction `pc` belongs to.
+    pub fn function_at(&self, pc: usize) -> usize {
+        self.functions.partition_point(|f| f.start <= pc) - 1
+    }
+}
+
+/// A subroutine call in progress.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub function: usize,  // index in `Program::functions`
+    pub return_pc: usize, // instruction following the call
+}
+
+/// Executes a program on the memory of the Hack platform, as the code of the standard VM translator would.
+/// The return addresses are kept in `frames` rather than on the stack, which gets 0 in their place.
+pub struct Vm {
+    pub program: Program,
+    pub ram: Vec<i16>,
+    pub pc: usize,
+    pub frames: Vec<Frame>, // innermost last
+    pub halted: bool,
+    pub steps: u64, // instructions executed
+    halt: Option<usize>,
+}
+
+impl Vm {
+    /// Starts the program at `Sys.init`, or at `Main.main` when it does not include the OS.
+    /// The program halts when the entry function returns or when it calls `Sys.halt`, which would loop forever.
+    pub fn new(program: Program) -> Result<Vm, String> {
+        let entry = program.function_index("Sys.init")
+            .or_else(|| program.function_index("Main.main"))
+            .ok_or("no Sys.init or Main.main function")?;
+        let halt = program.function_index("Sys.halt");
+        let mut vm = Vm { program, ram: vec![0; RAM_SIZE], pc: 0, frames: vec![], halted: false, steps: 0, halt };
+        vm.ram[SP] = STACK_START as i16;
+        vm.call(entry, 0)?;
+        Ok(vm)
+    }
+
+    /// The function being executed.
+    pub fn function(&self) -> &Function {
+        &self.program.functions[self.frames.last().expect("no frame").function]
+    }
+
+    /// Executes one instruction.
+    pub fn step(&mut self) -> Result<(), String> {
+        if self.halted {
+            return Err(String::from("the program has halted"));
+        }
+        if self.pc >= self.function().end {
+            return Err(format!("{} ends without returning", self.function().name));
+        }
+        let instruction = self.program.code[self.pc];
+        self.pc += 1;
+        self.steps += 1;
+        match instruction {
+            Instruction::Push(Segment::Const, i) => self.push(i as i16)?,
+            Instruction::Push(segment, i) => {
+                let value = self.ram[self.address(segment, i)?];
+                self.push(value)?;
+            },
+            Instruction::Pop(segment, i) => {
+                let value = self.pop()?;
+                let address = self.address(segment, i)?;
+                self.ram[address] = value;
+            },
+            Instruction::Arithmetic(Command::Neg) => {
+                let x = self.pop()?;
+                self.push(x.wrapping_neg())?;
+            },
+            Instruction::Arithmetic(Command::Not) => {
+                let x = self.pop()?;
+                self.push(!x)?;
+            },
+            Instruction::Arithmetic(command) => {
+                let y = self.pop()?;
+                let x = self.pop()?;
+                let truth = |b: bool| if b { -1 } else { 0 };
+                self.push(match command {
+                    Command::Add => x.wrapping_add(y),
+                    Command::Sub => x.wrapping_sub(y),
+                    Command::Eq  => truth(x == y),
+                    Command::Gt  => truth(x > y),
+                    Command::Lt  => truth(x < y),
+                    Command::And => x & y,
+                    Command::Or  => x | y,
+                    Command::Neg | Command::Not => unreachable!(),
+                })?;
+            },
+            Instruction::Label => (),
+            Instruction::Goto(target) => { self.pc = target; },
+            Instruction::IfGoto(target) => {
+                if self.pop()? != 0 {
+                    self.pc = target;
+                }
+            },
+            Instruction::Function(n_locals) => {
+                for _ in 0..n_locals {
+                    self.push(0)?;
+                }
+            },
+            Instruction::Call(function, n_args) => self.call(function, n_args)?,
+            Instruction::Return => self.ret()?,
+        }
+        Ok(())
+    }
+
+    /// The RAM address of `segment[index]` in the current frame.
+    pub fn address(&self, segment: Segment, index: u16) -> Result<usize, String> {
+        let base = |register: usize| self.ram[register] as u16 as usize;
+        let address = match segment {
+            Segment::Const   => { return Err(String::from("constant has no address")); },
+            Segment::Arg     => base(ARG) + index as usize,
+            Segment::Local   => base(LCL) + index as usize,
+            Segment::Static  => self.function().static_base + index as usize,
+            Segment::This    => base(THIS) + index as usize,
+            Segment::That    => base(THAT) + index as usize,
+            Segment::Pointer => THIS + index as usize,
+            Segment::Temp    => TEMP + index as usize,
+        };
+        match address < RAM_SIZE {
+            true  => Ok(address),
+            false => Err(format!("{} {} is at address {}, outside the memory", segment, index, address)),
+        }
+    }
+
+    fn push(&mut self, value: i16) -> Result<(), String> {
+        let sp = self.ram[SP] as u16 as usize;
+        if !(STACK_START..STACK_END).contains(&sp) {
+            return Err(String::from("stack overflow"));
+        }
+        self.ram[sp] = value;
+        self.ram[SP] += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i16, String> {
+        let sp = self.ram[SP] as u16 as usize;
+        if !(STACK_START + 1..=STACK_END).contains(&sp) {
+            return Err(String::from("stack underflow"));
+        }
+        self.ram[SP] -= 1;
+        Ok(self.ram[sp - 1])
+    }
+
+    fn call(&mut self, function: usize, n_args: u16) -> Result<(), String> {
+        if Some(function) == self.halt {
+            self.halted = true;
+            return Ok(());
+        }
+        self.push(0)?;
+        for register in [LCL, ARG, THIS, THAT] {
+            self.push(self.ram[register])?;
+        }
+        self.ram[ARG] = self.ram[SP] - n_args as i16 - 5;
+        self.ram[LCL] = self.ram[SP];
+        self.frames.push(Frame { function, return_pc: self.pc });
+        self.pc = self.program.functions[function].start;
+        Ok(())
+    }
+
+    fn ret(&mut self) -> Result<(), String> {
+        let frame = self.ram[LCL] as u16 as usize;
+        if !(STACK_START + 5..STACK_END).contains(&frame) {
+            return Err(String::from("return with a corrupted frame"));
+        }
+        let value = self.pop()?;
+        let arg = self.address(Segment::Arg, 0)?;
+        self.ram[arg] = value;
+        self.ram[SP] = arg as i16 + 1;
+        for (i, register) in [THAT, THIS, ARG, LCL].into_iter().enumerate() {
+            self.ram[register] = self.ram[frame - 1 - i];
+        }
+        let returning = self.frames.pop().expect("no frame");
+        match self.frames.is_empty() {
+            true  => { self.halted = true; },
+            false => { self.pc = returning.return_pc; },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(src: &str) -> Result<Program, String> {
+        Program::parse(&[(String::from("Main"), src.to_string())])
+    }
+
+    // Runs until the program halts, failing after `limit` instructions.
+    fn run(vm: &mut Vm, limit: u64) {
+        while !vm.halted {
+            vm.step().unwrap();
+            assert!(vm.steps < limit, "still running after {} instructions", limit);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(program("push constant 1\n").unwrap_err(), "Main.vm:1: command outside a function");
+        assert_eq!(program("function Main.main 0\npush local\n").unwrap_err(), "Main.vm:2: number expected in push local");
+        assert_eq!(program("function Main.main 0\npop temp 8\n").unwrap_err(), "Main.vm:2: temp 8 is out of range");
+        assert_eq!(program("function Main.main 0\ngoto END\nreturn\nfunction Main.f 0\nlabel END\n").unwrap_err(), "Main.vm:2: no label END in this function");
+        assert_eq!(program("function Main.main 0\ncall Main.f 0 // comment\n").unwrap_err(), "Main.vm:2: call to undefined function Main.f");
+        assert_eq!(program("function Main.main 0\npush that 0\nmul\n").unwrap_err(), "Main.vm:3: unknown command mul");
+    }
+
+    #[test]
+    fn test_run() {
+        // static 0 = sum of 1..=5 computed by a recursive function
+        let src = "function Main.main 0\npush constant 5\ncall Main.sum 1\npop static 0\npush constant 0\nreturn\n\
+                   function Main.sum 0\npush argument 0\nif-goto REC\npush constant 0\nreturn\nlabel REC\n\
+                   push argument 0\npush argument 0\npush constant 1\nsub\ncall Main.sum 1\nadd\nreturn\n";
+        let mut vm = Vm::new(program(src).unwrap()).unwrap();
+        assert_eq!(vm.function().name, "Main.main");
+        run(&mut vm, 1000);
+        assert_eq!(vm.ram[STATIC_START], 15);
+        assert_eq!(vm.steps, 66);
+        assert_eq!(vm.step(), Err(String::from("the program has halted")));
+
+        let mut vm = Vm::new(program("function Main.main 0\nlabel LOOP\npush constant 1\ngoto LOOP\n").unwrap()).unwrap();
+        let result = (0..10000).map(|_| vm.step()).find(|r| r.is_err());
+        assert_eq!(result, Some(Err(String::from("stack overflow"))));
+    }
+
+    #[test]
+    fn test_run_with_os() {
+        let mut vm = Vm::new(Program::load_dir(Path::new("./tests/Seven")).unwrap()).unwrap();
+        assert_eq!(vm.function().name, "Sys.init");
+        run(&mut vm, 10_000_000);
+        // Main.main printed 7 on the screen, then Sys.init called Sys.halt
+        assert!(vm.ram[16384..24576].iter().any(|w| *w != 0));
+        assert_eq!(vm.function().name, "Sys.init");
+    }
+}
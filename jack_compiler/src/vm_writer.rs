@@ -1,6 +1,7 @@
 use std::io::{BufWriter, Write};
-use std::fs::File;
 use std::fmt;
+use std::str::FromStr;
+use crate::tokenizer::Span;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Segment {
@@ -45,6 +46,25 @@ impl fmt::Display for Segment {
     }
 }
 
+impl FromStr for Segment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "constant" => Ok(Segment::Const),
+            "argument" => Ok(Segment::Arg),
+            "local"    => Ok(Segment::Local),
+            "static"   => Ok(Segment::Static),
+            "this"     => Ok(Segment::This),
+            "that"     => Ok(Segment::That),
+            "pointer"  => Ok(Segment::Pointer),
+            "temp"     => Ok(Segment::Temp),
+            _          => Err(format!("unknown segment {}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Command {
     Add,
     Sub,
@@ -91,51 +111,154 @@ impl fmt::Display for Command {
     }
 }
 
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "add" => Ok(Command::Add),
+            "sub" => Ok(Command::Sub),
+            "neg" => Ok(Command::Neg),
+            "eq"  => Ok(Command::Eq),
+            "gt"  => Ok(Command::Gt),
+            "lt"  => Ok(Command::Lt),
+            "and" => Ok(Command::And),
+            "or"  => Ok(Command::Or),
+            "not" => Ok(Command::Not),
+            _     => Err(format!("unknown command {}", s)),
+        }
+    }
+}
+
 pub struct VMWriter {
-    writer: BufWriter<File>,
+    writer: BufWriter<Box<dyn Write>>,
+    captures: Vec<Vec<(String, Span)>>,
+    annotate: bool,
+    location: Span,
+    functions: Vec<(String, Vec<Span>)>,
 }
 
 impl VMWriter {
-    pub fn new(f: File) -> Self {
+    pub fn new<W: Write + 'static>(f: W) -> Self {
         VMWriter {
-            writer: BufWriter::<File>::new(f),
+            writer: BufWriter::new(Box::new(f)),
+            captures: vec![],
+            annotate: false,
+            location: Span::default(),
+            functions: vec![],
+        }
+    }
+
+    /// Adds comments to the code: the ones passed to `write_comment` and the names of the variables
+    /// pushed and popped with `write_push_var` and `write_pop_var`. Off by default, for the reference tools.
+    pub fn set_annotate(&mut self, annotate: bool) {
+        self.annotate = annotate;
+    }
+
+    /// Collects the following commands instead of writing them, until the matching `end_capture`.
+    /// Used to emit code in a different order than it is compiled.
+    pub fn begin_capture(&mut self) {
+        self.captures.push(vec![]);
+    }
+
+    pub fn end_capture(&mut self) -> Vec<(String, Span)> {
+        self.captures.pop().expect("end_capture without begin_capture")
+    }
+
+    /// Writes commands collected by a capture, with the source locations they were collected at.
+    pub fn write_captured(&mut self, commands: Vec<(String, Span)>) {
+        for (c, location) in commands {
+            self.emit_at(c, location);
+        }
+    }
+
+    /// The source location of the following commands, until the next call.
+    pub fn set_location(&mut self, span: Span) {
+        self.location = span;
+    }
+
+    /// The functions written so far, each with the source location of its commands in order:
+    /// the `function` command first, then every command of its body except comments.
+    pub fn locations(&self) -> &[(String, Vec<Span>)] {
+        &self.functions
+    }
+
+    fn emit(&mut self, command: String) {
+        self.emit_at(command, self.location);
+    }
+
+    fn emit_at(&mut self, command: String, location: Span) {
+        if let Some(capture) = self.captures.last_mut() {
+            capture.push((command, location));
+            return;
+        }
+        writeln!(self.writer, "{}", command).unwrap();
+        if let Some(name) = command.strip_prefix("function ") {
+            let name = name.split(' ').next().unwrap_or_default();
+            self.functions.push((name.to_string(), vec![]));
+        }
+        if let (false, Some((_, locations))) = (command.starts_with("//"), self.functions.last_mut()) {
+            locations.push(location);
         }
     }
 
     pub fn write_push(&mut self, segment: Segment, index: i16) {
-        writeln!(self.writer, "push {} {}", segment.to_string(), index).unwrap();
+        self.emit(format!("push {} {}", segment, index));
     }
 
     pub fn write_pop(&mut self, segment: Segment, index: i16) {
-        writeln!(self.writer, "pop {} {}", segment.to_string(), index).unwrap();
+        self.emit(format!("pop {} {}", segment, index));
+    }
+
+    /// Same as `write_push` for the variable `name`.
+    pub fn write_push_var(&mut self, segment: Segment, index: i16, name: &str) {
+        match self.annotate {
+            true  => self.emit(format!("push {} {} // {}", segment, index, name)),
+            false => self.write_push(segment, index),
+        }
+    }
+
+    /// Same as `write_pop` for the variable `name`.
+    pub fn write_pop_var(&mut self, segment: Segment, index: i16, name: &str) {
+        match self.annotate {
+            true  => self.emit(format!("pop {} {} // {}", segment, index, name)),
+            false => self.write_pop(segment, index),
+        }
+    }
+
+    /// A comment line, written only when annotating.
+    pub fn write_comment(&mut self, text: &str) {
+        if self.annotate {
+            self.emit(format!("// {}", text));
+        }
     }
 
     pub fn write_arithmetic(&mut self, command: Command) {
-       writeln!(self.writer, "{}", command.to_string()).unwrap();
+        self.emit(command.to_string());
     }
 
     pub fn write_label(&mut self, label: &str) {
-        writeln!(self.writer, "label {}", label).unwrap();
+        self.emit(format!("label {}", label));
     }
 
     pub fn write_goto(&mut self, label: &str) {
-        writeln!(self.writer, "goto {}", label).unwrap();
+        self.emit(format!("goto {}", label));
     }
 
     pub fn write_if(&mut self, label: &str) {
-        writeln!(self.writer, "if-goto {}", label).unwrap();
+        self.emit(format!("if-goto {}", label));
     }
 
     pub fn write_call(&mut self, name: &str, n_args: i16) {
-        writeln!(self.writer, "call {} {}", name, n_args).unwrap();
+        self.emit(format!("call {} {}", name, n_args));
     }
 
     pub fn write_function(&mut self, name: &str, n_locals: i16) {
-        writeln!(self.writer, "function {} {}", name, n_locals).unwrap();
+        self.emit(format!("function {} {}", name, n_locals));
     }
 
     pub fn write_return(&mut self) {
-        writeln!(self.writer, "return").unwrap();
+        self.emit(String::from("return"));
     }
 
     pub fn close(&mut self) {